@@ -2,7 +2,9 @@ use std::{
     fs,
     thread,
     sync::mpsc::{self, TryRecvError},
-    path::PathBuf
+    path::PathBuf,
+    str::FromStr,
+    ops::RangeInclusive
 };
 
 use microui_femtovg::microui::{*, const_vec::ConstStr};
@@ -10,37 +12,118 @@ use rfd::FileDialog;
 use nohash_hasher::IntMap;
 
 use crate::{
-    midi_file::{MidiFile, Mapping},
+    midi_file::{
+        MidiFile, Mapping, Zone, Target, TargetKind, Output, VelocityTransform, Threshold, Rule,
+        Scale, Rounding, Chords, Chord, ChordNotes, ChordOutput, Articulation, Rudiment,
+        Generator, Ornament, PoolStrategy, Tuning, Retune, RetuneMode,
+        MpeZone, Split, SplitPart, Dynamics, Histogram, Quantize,
+        Patch, CollisionPolicy, UnmappedPolicy, ControllerTarget, Span, note_key, apply_rules
+    },
     Result, Error
 };
 
 const PANEL_NAME: &str = "inputs";
 const MAP_WINDOW_NAME: &str = "Confirm mapping";
+const COLLISION_POLICIES: [&str; 4] = ["Ignore", "Keep loudest", "Merge", "Retrigger window"];
+const UNMAPPED_POLICIES: [&str; 3] = ["Keep", "Drop", "Fallback"];
+const SPAN_UNITS: [&str; 2] = ["Ticks", "ms"];
+const RULE_KINDS: [&str; 3] = ["Transpose", "Shift range", "Pitch class"];
+const SCALES: [&str; 9] = [
+    "Off", "Major", "Minor", "Dorian", "Phrygian",
+    "Lydian", "Mixolydian", "Locrian", "Custom"
+];
+const SCALE_INTERVALS: [[u8; 7]; 7] = [
+    [0, 2, 4, 5, 7, 9, 11],
+    [0, 2, 3, 5, 7, 8, 10],
+    [0, 2, 3, 5, 7, 9, 10],
+    [0, 1, 3, 5, 7, 8, 10],
+    [0, 2, 4, 6, 7, 9, 11],
+    [0, 2, 4, 5, 7, 9, 10],
+    [0, 1, 3, 5, 6, 8, 10]
+];
+const ROUNDINGS: [&str; 3] = ["Nearest", "Up", "Down"];
+const CHORD_KINDS: [&str; 2] = ["Notes", "Intervals"];
+const CHORD_OUTPUTS: [&str; 2] = ["Output", "Root + marker"];
+const RUDIMENTS: [&str; 2] = ["Flam", "Drag"];
+const ORNAMENTS: [&str; 4] = ["None", "Flam", "Drag", "Roll"];
+const TARGET_KINDS: [&str; 4] = ["Note", "Controller", "CC switch", "Pool"];
+const POOL_STRATEGIES: [&str; 2] = ["Cycle", "Random"];
+const RETUNE_MODES: [&str; 2] = ["Pitch bend", "MTS dump"];
+const DYNAMICS_MODES: [&str; 3] = ["Off", "Normalize", "Compress"];
+const QUANTIZE_GRIDS: [&str; 7] = ["Off", "1/4", "1/8", "1/16", "1/32", "1/8 T", "1/16 T"];
+/// The number of grid lines in a beat for each of the grids.
+const QUANTIZE_DIVISIONS: [u16; 7] = [0, 1, 2, 4, 8, 3, 6];
+const VELOCITY_MODES: [&str; 5] = ["Unchanged", "Linear", "Clamp", "Curve", "Fixed"];
 
 #[derive(Default)]
 pub struct State {
     current: VisibleTracks,
-    tracks: Vec<Vec<InputState>>,
+    tracks: Vec<TrackState>,
     tracks_state: TracksState,
-    map_window: Option<MapWindowState>
+    controller_options: Vec<String>,
+    map_window: Option<MapWindowState>,
+    /// The MPE zones of each track.
+    mpe_zones: Vec<Vec<MpeZone>>
 }
 
 #[derive(Debug)]
 pub enum Event {
     MidiLoaded(MidiFile),
     MidiLoadErr(Error),
+    MapErr(Error),
     OutputSelected(SelectedOutput),
     Map {
         mappings: Vec<Mapping>,
         file: PathBuf
-    }
+    },
+    /// Asks for the velocities before and after the dynamics are applied.
+    Preview(Vec<Mapping>)
 }
 
 #[derive(Debug)]
 pub struct SelectedOutput {
     pub output: usize,
-    track: usize,
-    index: usize
+    slot: OutputSlot
+}
+
+/// Where the selected output goes.
+#[derive(Debug)]
+enum OutputSlot {
+    Layer {
+        track: usize,
+        index: usize,
+        zone: usize,
+        layer: usize
+    },
+    Switch {
+        track: usize,
+        index: usize,
+        zone: usize,
+        layer: usize,
+        switch: usize
+    },
+    Pool {
+        track: usize,
+        index: usize,
+        zone: usize,
+        layer: usize,
+        entry: usize
+    },
+    Threshold {
+        track: usize,
+        controller: usize,
+        threshold: usize
+    },
+    Chord {
+        track: usize,
+        chord: usize
+    },
+    Articulation {
+        track: usize,
+        index: usize,
+        articulation: usize
+    },
+    Fallback
 }
 
 #[derive(Clone, Copy)]
@@ -58,41 +141,297 @@ enum TracksState {
     }
 }
 
+struct TrackState {
+    rules: Vec<RuleState>,
+    scale: ScaleState,
+    tuning: TuningState,
+    quantize: QuantizeState,
+    split: SplitState,
+    dynamics: DynamicsState,
+    chords: ChordsState,
+    inputs: Vec<InputState>,
+    controllers: Vec<ControllerState>,
+    programs: Vec<ProgramState>
+}
+
+struct RuleState {
+    kind: dropdown::State,
+    first: ConstStr<4>,
+    second: ConstStr<4>,
+    third: ConstStr<4>
+}
+
+struct ScaleState {
+    mode: dropdown::State,
+    root: ConstStr<4>,
+    rounding: dropdown::State,
+    /// The pitch classes of a custom scale.
+    custom: ConstStr<48>
+}
+
+struct TuningState {
+    /// The file name and the contents of the Scala scale.
+    scale: Option<(String, String)>,
+    /// The file name and the contents of the keyboard mapping.
+    keyboard_map: Option<(String, String)>,
+    mode: dropdown::State,
+    /// The pitch bend range in semitones.
+    range: ConstStr<4>,
+    first_channel: ConstStr<4>,
+    last_channel: ConstStr<4>
+}
+
+struct QuantizeState {
+    grid: dropdown::State,
+    /// In percent, like the swing and the window.
+    strength: ConstStr<4>,
+    swing: ConstStr<4>,
+    window: ConstStr<4>
+}
+
+struct SplitState {
+    enabled: bool,
+    /// The first key of the upper part.
+    point: ConstStr<4>,
+    /// The number of keys below the split point that the upper part plays too.
+    layer: ConstStr<4>,
+    lower: SplitPartState,
+    upper: SplitPartState
+}
+
+struct SplitPartState {
+    /// Keeps the channel when empty.
+    channel: ConstStr<4>,
+    transpose: ConstStr<4>,
+    new_track: bool
+}
+
+struct DynamicsState {
+    mode: dropdown::State,
+    /// The lowest velocity or the threshold.
+    first: ConstStr<4>,
+    /// The highest velocity or the ratio.
+    second: ConstStr<8>,
+    median: ConstStr<4>
+}
+
+struct ChordsState {
+    tolerance: ConstStr<8>,
+    unit: dropdown::State,
+    patterns: Vec<ChordState>
+}
+
+struct ChordState {
+    /// Exact notes or intervals above the lowest note.
+    kind: dropdown::State,
+    notes: ConstStr<32>,
+    /// An output note or the root note with a marker.
+    output: dropdown::State,
+    map_to: OutputSelect,
+    marker: ConstStr<16>
+}
+
 struct InputState {
     note: wmidi::Note,
+    channel: u8,
     alias: ConstStr<16>,
+    zones: Vec<ZoneState>,
+    articulations: Vec<ArticulationState>
+}
+
+struct ArticulationState {
+    rudiment: dropdown::State,
+    window: ConstStr<8>,
+    unit: dropdown::State,
+    /// Only detect the rudiment when the grace notes are softer.
+    softer: bool,
+    map_to: OutputSelect
+}
+
+/// The velocity range is only shown and used when the input has more than
+/// one zone, otherwise the zone covers all velocities.
+struct ZoneState {
+    min: ConstStr<4>,
+    max: ConstStr<4>,
+    layers: Vec<LayerState>
+}
+
+struct LayerState {
+    /// Note, controller, an output picked by a controller value or
+    /// a pool of outputs.
+    kind: dropdown::State,
+    map_to: OutputSelect,
+    controller: ConstStr<4>,
+    /// The controller value, uses the velocity when empty.
+    value: ConstStr<4>,
+    switches: Vec<SwitchState>,
+    /// Cycle or random.
+    strategy: dropdown::State,
+    seed: ConstStr<20>,
+    pool: Vec<OutputSelect>,
+    velocity: VelocityState,
+    generator: GeneratorState
+}
+
+/// An output of a switch layer, played from the controller value on.
+struct SwitchState {
+    from: ConstStr<4>,
+    map_to: OutputSelect
+}
+
+/// A dropdown of the outputs where the first entry is "None".
+struct OutputSelect {
+    dropdown: dropdown::State,
+    output: Option<Output>
+}
+
+struct GeneratorState {
+    ornament: dropdown::State,
+    offset: ConstStr<8>,
+    unit: dropdown::State,
+    /// The velocity of the added strokes relative to the hit.
+    velocity: ConstStr<8>
+}
+
+struct VelocityState {
+    mode: dropdown::State,
+    first: ConstStr<8>,
+    second: ConstStr<8>
+}
+
+struct ControllerState {
+    controller: u8,
+    /// Keep, drop, notes or one of the 128 controllers.
     map_to_dropdown: dropdown::State,
-    map_to: Option<wmidi::Note>
+    thresholds: Vec<ThresholdState>
+}
+
+struct ThresholdState {
+    value: ConstStr<4>,
+    map_to: OutputSelect,
+    /// Uses the controller value when empty.
+    velocity: ConstStr<4>
+}
+
+/// Empty fields keep the program and don't send bank selects.
+struct ProgramState {
+    patch: Patch,
+    bank_msb: ConstStr<4>,
+    bank_lsb: ConstStr<4>,
+    program: ConstStr<4>
 }
 
 struct MapWindowState {
-    active_tracks: Vec<bool>
+    active_tracks: Vec<bool>,
+    collisions: dropdown::State,
+    retrigger_window: ConstStr<8>,
+    retrigger_unit: dropdown::State,
+    unmapped: dropdown::State,
+    fallback: OutputSelect,
+    /// The velocities of each track before and after the dynamics. Cleared
+    /// once any field that the mappings are built from is edited.
+    histograms: Vec<(usize, Histogram, Histogram)>
 }
 
 impl State {
     #[inline]
     pub fn reset_mapping(&mut self, note: wmidi::Note, removed_at: usize) {
-        for states in &mut self.tracks {
-            for state in states {
-                if let Some(mapped) = state.map_to {
-                    if mapped == note {
-                        state.map_to = None;
-                        state.map_to_dropdown.index = Some(0);
-                    }
-                }
+        self.for_each_output(|x| x.reset(note, removed_at));
+        self.clear_histograms();
+    }
 
-                let index = state.map_to_dropdown.index.as_mut().unwrap();
-                if *index >= removed_at && *index > 1 {
-                    *index -= 1;
-                }
+    /// Updates the mappings that use the output at the given index
+    /// after it has been edited.
+    #[inline]
+    pub fn update_output(&mut self, index: usize, output: Output) {
+        self.for_each_output(|x| {
+            if x.dropdown.index == Some(index + 1) {
+                x.output = Some(output);
             }
+        });
+        self.clear_histograms();
+    }
+
+    /// Shows the velocities of a preview in the map window.
+    #[inline]
+    pub fn set_histograms(&mut self, histograms: Vec<(usize, Histogram, Histogram)>) {
+        if let Some(window) = self.map_window.as_mut() {
+            window.histograms = histograms;
+        }
+    }
+
+    /// Hides the velocities of a preview once the mappings have changed.
+    #[inline]
+    fn clear_histograms(&mut self) {
+        if let Some(window) = self.map_window.as_mut() {
+            window.histograms.clear();
         }
     }
 
     #[inline]
-    pub fn set_mapping(&mut self, selection: SelectedOutput, note: Option<wmidi::Note>) {
-        let SelectedOutput { track, index, .. } = selection;
-        self.tracks[track][index].map_to = note;
+    pub fn set_mapping(&mut self, selection: SelectedOutput, output: Option<Output>) {
+        let select = match selection.slot {
+            OutputSlot::Layer { track, index, zone, layer } =>
+                &mut self.tracks[track].inputs[index].zones[zone].layers[layer].map_to,
+            OutputSlot::Switch { track, index, zone, layer, switch } =>
+                &mut self.tracks[track].inputs[index].zones[zone].layers[layer]
+                    .switches[switch].map_to,
+            OutputSlot::Pool { track, index, zone, layer, entry } =>
+                &mut self.tracks[track].inputs[index].zones[zone].layers[layer].pool[entry],
+            OutputSlot::Threshold { track, controller, threshold } =>
+                &mut self.tracks[track].controllers[controller].thresholds[threshold].map_to,
+            OutputSlot::Chord { track, chord } =>
+                &mut self.tracks[track].chords.patterns[chord].map_to,
+            OutputSlot::Articulation { track, index, articulation } =>
+                &mut self.tracks[track].inputs[index].articulations[articulation].map_to,
+            OutputSlot::Fallback => match self.map_window.as_mut() {
+                Some(window) => &mut window.fallback,
+                None => return
+            }
+        };
+
+        select.output = output;
+        self.clear_histograms();
+    }
+
+    fn for_each_output(&mut self, mut f: impl FnMut(&mut OutputSelect)) {
+        for track in &mut self.tracks {
+            let layers = track.inputs.iter_mut()
+                .flat_map(|x| &mut x.zones)
+                .flat_map(|x| &mut x.layers);
+
+            for layer in layers {
+                f(&mut layer.map_to);
+
+                for switch in &mut layer.switches {
+                    f(&mut switch.map_to);
+                }
+
+                for entry in &mut layer.pool {
+                    f(entry);
+                }
+            }
+
+            let articulations = track.inputs.iter_mut().flat_map(|x| &mut x.articulations);
+
+            for articulation in articulations {
+                f(&mut articulation.map_to);
+            }
+
+            let thresholds = track.controllers.iter_mut().flat_map(|x| &mut x.thresholds);
+
+            for threshold in thresholds {
+                f(&mut threshold.map_to);
+            }
+
+            for chord in &mut track.chords.patterns {
+                f(&mut chord.map_to);
+            }
+        }
+
+        if let Some(window) = self.map_window.as_mut() {
+            f(&mut window.fallback);
+        }
     }
 
     pub fn draw<'a>(
@@ -156,6 +495,8 @@ impl State {
                             });
 
                             self.tracks = init_tracks(&midi);
+                            self.controller_options = controller_options();
+                            self.mpe_zones = midi.mpe_zones.clone();
                             event = Some(Event::MidiLoaded(midi));
                         },
                         Ok(Err(err)) => {
@@ -187,41 +528,49 @@ impl State {
                     self.init_map_window();
                 }
 
-                if let Some(e) = self.draw_map_window(ctx, screen) {
+                let outputs: Vec<&'a str> = outputs.collect();
+
+                if let Some(e) = self.draw_map_window(ctx, screen, &outputs) {
                     event = Some(e);
                 }
 
+                let mut edited = false;
+
                 ctx.layout_row(&[-1], -1);
                 Panel::new(PANEL_NAME).show(ctx, |ctx| {
-                    let outputs: Vec<&'a str> = outputs.collect();
-
-                    if let Some(e) = self.draw_entries(ctx, &outputs) {
+                    if let Some(e) = self.draw_entries(ctx, &outputs, &mut edited) {
                         event = Some(e);
                     }
                 });
+
+                if edited {
+                    self.clear_histograms();
+                }
             }
         }
 
         event
     }
 
+    /// Sets `edited` when a field that the mappings are built from changes.
     fn draw_entries<'a>(
         &mut self,
         ctx: &mut Context,
-        outputs: &'a [&'a str]
+        outputs: &'a [&'a str],
+        edited: &mut bool
     ) -> Option<Event> {
         let mut event: Option<Event> = None;
 
         match self.current {
             VisibleTracks::All => {
                 for i in 0..self.tracks.len() {
-                    if let Some(e) = self.draw_inputs(ctx, outputs, i) {
+                    if let Some(e) = self.draw_inputs(ctx, outputs, i, edited) {
                         event = Some(e);
                     }
                 }
             },
             VisibleTracks::Single(index) =>
-                event = self.draw_inputs(ctx, outputs, index),
+                event = self.draw_inputs(ctx, outputs, index, edited),
         }
 
         event
@@ -231,7 +580,8 @@ impl State {
         &mut self,
         ctx: &mut Context,
         outputs: &'a [&'a str],
-        track: usize
+        track: usize,
+        edited: &mut bool
     ) -> Option<Event> {
         let mut event: Option<Event> = None;
 
@@ -239,30 +589,174 @@ impl State {
         let label_width = 53;
         let box_width = 150;
 
-        for (index, state) in self.tracks[track].iter_mut().enumerate() {
-            ctx.layout_row(&[label_width, 100], 0);
+        let rules = self.tracks[track].draw_rules(ctx, label_width, edited);
+        let scale = self.tracks[track].scale.draw(ctx, label_width, edited);
+
+        if let Some(err) = self.tracks[track].tuning.draw(ctx, label_width, box_width, edited) {
+            event = Some(Event::MapErr(err));
+        }
+
+        self.tracks[track].quantize.draw(ctx, label_width, edited);
+        self.tracks[track].split.draw(ctx, label_width, box_width, edited);
+        self.tracks[track].dynamics.draw(ctx, label_width, edited);
+
+        if let Some((chord, output)) = self.tracks[track].chords.draw(
+            ctx,
+            outputs,
+            label_width,
+            box_width,
+            edited
+        ) {
+            event = Some(Event::OutputSelected(
+                SelectedOutput {
+                    output,
+                    slot: OutputSlot::Chord {
+                        track,
+                        chord
+                    }
+                }
+            ));
+        }
+
+        ctx.layout_row(&[-1], 1);
+
+        let rect = ctx.layout_next();
+        ctx.draw_box(rect, separator_color);
+
+        for (index, state) in self.tracks[track].inputs.iter_mut().enumerate() {
+            let mapped = state.zones.iter()
+                .filter(|x| x.layers.iter().any(LayerState::is_mapped))
+                .count();
+
+            ctx.layout_row(&[label_width, 40, -1], 0);
             ctx.label("Note:");
             ctx.label(state.note.to_string());
+
+            let key = state.note as u8;
+            let by_rule = apply_rules(&rules, None, key).is_some();
+            let moved = apply_rules(&rules, scale.as_ref(), key)
+                .map(|x| unsafe { wmidi::Note::from_u8_unchecked(x) });
+
+            match moved {
+                Some(note) if mapped < state.zones.len() && by_rule =>
+                    ctx.label(format!("-> {note} (rule)")),
+                Some(note) if mapped < state.zones.len() && note != state.note =>
+                    ctx.label(format!("-> {note} (scale)")),
+                Some(_) if mapped < state.zones.len() => ctx.label("(in scale)"),
+                _ if mapped == 0 => ctx.label("(unmapped)"),
+                _ if mapped < state.zones.len() => ctx.label("(partially unmapped)"),
+                _ => ctx.label("")
+            }
+
+            ctx.layout_row(&[label_width, -1], 0);
+            ctx.label("Channel:");
+
+            match self.mpe_zones[track].iter().find(|x| x.manager == state.channel) {
+                Some(zone) => ctx.label(format!(
+                    "{} (MPE, members {} - {})",
+                    state.channel + 1,
+                    zone.members.start() + 1,
+                    zone.members.end() + 1
+                )),
+                None => ctx.label((state.channel + 1).to_string())
+            }
     
             ctx.layout_row(&[label_width, box_width], 0);
             ctx.label("Alias:");
             ctx.textbox(&mut state.alias);
 
+            let mut removed: Option<usize> = None;
+            let has_ranges = state.zones.len() > 1;
+
+            for (zone, zone_state) in state.zones.iter_mut().enumerate() {
+                ctx.push_id(&(zone_state as *const ZoneState));
+
+                if has_ranges {
+                    ctx.layout_row(&[label_width, 40, 40, 20], 0);
+                    ctx.label("Velocity:");
+                    *edited |= ctx.textbox(&mut zone_state.min).change;
+                    *edited |= ctx.textbox(&mut zone_state.max).change;
+
+                    if ctx.w(
+                        Button::icon(Icon::Close)
+                            .no_frame()
+                            .with_cursor()
+                    ).submit {
+                        removed = Some(zone);
+                        *edited = true;
+                    }
+                }
+
+                if let Some((layer, entry, output)) = zone_state.draw(
+                    ctx,
+                    outputs,
+                    label_width,
+                    box_width,
+                    edited
+                ) {
+                    let is_pool = zone_state.layers[layer].kind.index == Some(3);
+
+                    let slot = match entry {
+                        Some(entry) if is_pool => OutputSlot::Pool {
+                            track,
+                            index,
+                            zone,
+                            layer,
+                            entry
+                        },
+                        Some(switch) => OutputSlot::Switch {
+                            track,
+                            index,
+                            zone,
+                            layer,
+                            switch
+                        },
+                        None => OutputSlot::Layer {
+                            track,
+                            index,
+                            zone,
+                            layer
+                        }
+                    };
+
+                    event = Some(Event::OutputSelected(
+                        SelectedOutput {
+                            output,
+                            slot
+                        }
+                    ));
+                }
+                ctx.pop_id();
+            }
+
+            if let Some(zone) = removed {
+                state.zones.remove(zone);
+            }
+
             ctx.layout_row(&[label_width, box_width], 0);
-            ctx.label("Map to:");
-            
+            ctx.label("");
+
             ctx.push_id(&(state as *const InputState));
-            if ctx.w(Dropdown::new(
-                    &mut state.map_to_dropdown,
-                    &outputs
-                ).visible_items(10)
-            ).submit {
-                let output = state.map_to_dropdown.index.unwrap();
+            if ctx.button("Add velocity zone") {
+                state.zones.push(ZoneState::default());
+                *edited = true;
+            }
+
+            if let Some((articulation, output)) = state.draw_articulations(
+                ctx,
+                outputs,
+                label_width,
+                box_width,
+                edited
+            ) {
                 event = Some(Event::OutputSelected(
                     SelectedOutput {
                         output,
-                        track,
-                        index
+                        slot: OutputSlot::Articulation {
+                            track,
+                            index,
+                            articulation
+                        }
                     }
                 ));
             }
@@ -274,11 +768,94 @@ impl State {
             ctx.draw_box(rect, separator_color);
         }
 
+        let controllers = &mut self.tracks[track].controllers;
+
+        if !controllers.is_empty() {
+            ctx.layout_row(&[-1], 0);
+            ctx.label("Controllers:");
+
+            for (controller, state) in controllers.iter_mut().enumerate() {
+                ctx.layout_row(&[label_width, box_width], 0);
+                ctx.label(format!("CC {}", state.controller));
+
+                ctx.push_id(&(state as *const ControllerState));
+                *edited |= ctx.w(Dropdown::new(
+                        &mut state.map_to_dropdown,
+                        &self.controller_options
+                    ).visible_items(10)
+                ).submit;
+
+                if state.map_to_dropdown.index == Some(2) {
+                    if let Some((threshold, output)) = state.draw_thresholds(
+                        ctx,
+                        outputs,
+                        label_width,
+                        box_width,
+                        edited
+                    ) {
+                        event = Some(Event::OutputSelected(
+                            SelectedOutput {
+                                output,
+                                slot: OutputSlot::Threshold {
+                                    track,
+                                    controller,
+                                    threshold
+                                }
+                            }
+                        ));
+                    }
+                }
+                ctx.pop_id();
+            }
+
+            ctx.layout_row(&[-1], 1);
+
+            let rect = ctx.layout_next();
+            ctx.draw_box(rect, separator_color);
+        }
+
+        let programs = &mut self.tracks[track].programs;
+
+        if !programs.is_empty() {
+            ctx.layout_row(&[-1], 0);
+            ctx.label("Programs:");
+
+            ctx.layout_row(&[label_width, 40, 40, 40], 0);
+            ctx.label("");
+            ctx.label("MSB");
+            ctx.label("LSB");
+            ctx.label("Prog.");
+
+            for state in programs {
+                ctx.layout_row(&[-1], 0);
+                ctx.label(patch_label(&state.patch));
+
+                ctx.layout_row(&[label_width, 40, 40, 40], 0);
+                ctx.label("Map to:");
+                *edited |= ctx.textbox(&mut state.bank_msb).change;
+                *edited |= ctx.textbox(&mut state.bank_lsb).change;
+                *edited |= ctx.textbox(&mut state.program).change;
+            }
+
+            ctx.layout_row(&[-1], 1);
+
+            let rect = ctx.layout_next();
+            ctx.draw_box(rect, separator_color);
+        }
+
         event
     }
 
-    fn draw_map_window(&mut self, ctx: &mut Context, screen: Vec2) -> Option<Event> {
+    fn draw_map_window<'a>(
+        &mut self,
+        ctx: &mut Context,
+        screen: Vec2,
+        outputs: &'a [&'a str]
+    ) -> Option<Event> {
         const PANEL_HEIGHT: i32 = 105;
+        const OPTIONS_HEIGHT: i32 = 105;
+        const HISTOGRAMS_HEIGHT: i32 = 120;
+        const WIDTH: i32 = 260;
 
         let Some(window) = self.map_window.as_mut() else {
             return None;
@@ -295,17 +872,34 @@ impl State {
         ctx.container_mut(index).open = true;
 
         let mut event: Option<Event> = None;
+        let mut edited = false;
+
+        let row_height = ctx.style.size.y +
+            (ctx.style.padding as i32 * 2) +
+            ctx.style.spacing as i32;
+
+        let has_dynamics = self.tracks.iter()
+            .any(|x| x.dynamics.mode.index.is_some_and(|x| x > 0));
+
+        let velocities_height = match (has_dynamics, window.histograms.is_empty()) {
+            (false, _) => 0,
+            (true, true) => row_height,
+            (true, false) => HISTOGRAMS_HEIGHT + row_height
+        };
 
         let height = PANEL_HEIGHT +
+            OPTIONS_HEIGHT +
+            velocities_height +
+            row_height +
             ctx.style.size.x +
             (ctx.style.padding as i32 * 2) +
             ctx.style.spacing as i32;
 
         let screen = vec2(screen.x / 2, screen.y / 2);
         let window_rect = rect(
-            screen.x - 100,
+            screen.x - (WIDTH / 2),
             screen.y - (height / 2),
-            200,
+            WIDTH,
             height
         );
 
@@ -321,52 +915,83 @@ impl State {
                 ctx.layout_row(&[-1], 0);
                 for (i, track) in window.active_tracks.iter_mut().enumerate() {
                     let label = format!("Track {}", i + 1);
+                    let active = *track;
                     ctx.checkbox(label, track);
+                    edited |= active != *track;
                 }
             });
 
             ctx.layout_row(&[-1], 0);
-            if ctx.button("Execute") {
-                let file = FileDialog::new()
-                    .add_filter("MIDI", &["midi", "mid"])
-                    .save_file()
-                    .and_then(|mut x| {
-                        x.set_extension("mid");
+            ctx.label("Options:");
+
+            ctx.layout_row(&[-1], OPTIONS_HEIGHT);
+            Panel::new("Map options panel").show(ctx, |ctx| {
+                if let Some(output) = window.draw_options(ctx, outputs, &mut edited) {
+                    event = Some(Event::OutputSelected(
+                        SelectedOutput {
+                            output,
+                            slot: OutputSlot::Fallback
+                        }
+                    ));
+                }
+            });
+
+            if has_dynamics {
+                ctx.layout_row(&[150, -1], 0);
+                ctx.label("Velocities:");
 
-                        Some(x)
+                if ctx.button("Preview") {
+                    event = Some(match build_mappings(&self.tracks, window) {
+                        Ok(mappings) => Event::Preview(mappings),
+                        Err(err) => Event::MapErr(err)
                     });
+                }
 
-                if let Some(file) = file {
-                    let len = window.active_tracks.iter().filter(|x| **x).count();
-                    let mut mappings = Vec::with_capacity(len);
-    
-                    for (i, track) in self.tracks.iter().enumerate() {
-                        if !window.active_tracks[i] {
-                            continue;
-                        }
-    
-                        let mut map = IntMap::default();
-    
-                        for state in track {
-                            if let Some(to) = state.map_to {
-                                map.insert(state.note as u8, to);
-                            }
+                if !window.histograms.is_empty() {
+                    ctx.layout_row(&[-1], HISTOGRAMS_HEIGHT);
+                    Panel::new("Map velocities panel").show(ctx, |ctx| {
+                        for (track, before, after) in &window.histograms {
+                            ctx.layout_row(&[110, 110], 0);
+                            ctx.label(format!("Track {} before", track + 1));
+                            ctx.label("After");
+
+                            ctx.layout_row(&[110, 110], 40);
+                            draw_histogram(ctx, before);
+                            draw_histogram(ctx, after);
                         }
-    
-                        mappings.push(Mapping {
-                            track: i,
-                            map
-                        });
-                    }
-    
-                    event = Some(Event::Map {
-                        mappings, 
-                        file
                     });
                 }
             }
+
+            ctx.layout_row(&[-1], 0);
+            if ctx.button("Execute") {
+                match build_mappings(&self.tracks, window) {
+                    Ok(mappings) => {
+                        let file = FileDialog::new()
+                            .add_filter("MIDI", &["midi", "mid"])
+                            .save_file()
+                            .and_then(|mut x| {
+                                x.set_extension("mid");
+
+                                Some(x)
+                            });
+
+                        if let Some(file) = file {
+                            event = Some(Event::Map {
+                                mappings, 
+                                file
+                            });
+                        }
+                    },
+                    Err(err) => event = Some(Event::MapErr(err))
+                }
+            }
         });
 
+        if edited {
+            window.histograms.clear();
+        }
+
         if !ctx.container(index).open {
             self.map_window = None;
         }
@@ -386,37 +1011,1526 @@ impl State {
         };
 
         self.map_window = Some(MapWindowState {
-           active_tracks 
+            active_tracks,
+            collisions: dropdown::State::with_selection(0),
+            retrigger_window: ConstStr::new(),
+            retrigger_unit: dropdown::State::with_selection(0),
+            unmapped: dropdown::State::with_selection(0),
+            fallback: OutputSelect::default(),
+            histograms: Vec::new()
+        });
+    }
+}
+
+fn build_mappings(
+    tracks: &[TrackState],
+    window: &MapWindowState
+) -> Result<Vec<Mapping>> {
+    let len = window.active_tracks.iter().filter(|x| **x).count();
+    let mut mappings = Vec::with_capacity(len);
+    let collisions = window.collision_policy()?;
+    let unmapped = window.unmapped_policy()?;
+
+    for (i, track) in tracks.iter().enumerate() {
+        if !window.active_tracks[i] {
+            continue;
+        }
+
+        let rules = track.rules.iter()
+            .map(RuleState::rule)
+            .collect::<Result<Vec<Rule>>>()?;
+
+        let mut map = IntMap::default();
+        let mut articulations = Vec::new();
+
+        for state in &track.inputs {
+            let mut zones = Vec::with_capacity(state.zones.len());
+
+            for zone in &state.zones {
+                let velocity = if state.zones.len() > 1 {
+                    parse_velocity(&zone.min, 1)?..=parse_velocity(&zone.max, 127)?
+                } else {
+                    1..=127
+                };
+
+                let mut targets = Vec::with_capacity(zone.layers.len());
+
+                for layer in &zone.layers {
+                    if let Some(kind) = layer.target_kind()? {
+                        targets.push(Target {
+                            kind,
+                            velocity: layer.velocity.transform()?,
+                            generator: layer.generator.generator()?
+                        });
+                    }
+                }
+
+                zones.push(Zone {
+                    velocity,
+                    targets
+                });
+            }
+
+            if zones.iter().any(|x| !x.targets.is_empty()) {
+                map.insert(note_key(state.channel, state.note as u8), zones);
+            }
+
+            articulations.extend(state.articulations()?);
+        }
+
+        let mut controllers = IntMap::default();
+
+        for state in &track.controllers {
+            let target = match state.map_to_dropdown.index.unwrap_or(0) {
+                0 => continue,
+                1 => ControllerTarget::Drop,
+                2 => {
+                    let mut thresholds = Vec::with_capacity(state.thresholds.len());
+
+                    for threshold in &state.thresholds {
+                        let Some(output) = threshold.map_to.output else {
+                            continue;
+                        };
+
+                        let value: u8 = parse_field(&threshold.value, "Threshold", 64)?;
+
+                        if !(1..=127).contains(&value) {
+                            return Err(Error::Mapping(format!(
+                                "CC {}: threshold {value} is not in the range of 1 - 127.",
+                                state.controller
+                            )));
+                        }
+
+                        thresholds.push(Threshold {
+                            value,
+                            output,
+                            velocity: match threshold.velocity.as_str().trim().is_empty() {
+                                true => None,
+                                false => Some(parse_velocity(&threshold.velocity, 127)?)
+                            }
+                        });
+                    }
+
+                    ControllerTarget::Notes(thresholds)
+                },
+                index => ControllerTarget::Controller((index - 3) as u8)
+            };
+
+            controllers.insert(state.controller, target);
+        }
+
+        let mut programs = Vec::new();
+
+        for state in &track.programs {
+            let fields = [&state.bank_msb, &state.bank_lsb, &state.program];
+
+            if fields.iter().all(|x| x.as_str().trim().is_empty()) {
+                continue;
+            }
+
+            // The fields that are left empty keep the bank and program of the patch.
+            let to = Patch {
+                bank_msb: parse_data_byte(&state.bank_msb, "Bank select MSB")?
+                    .or(state.patch.bank_msb),
+                bank_lsb: parse_data_byte(&state.bank_lsb, "Bank select LSB")?
+                    .or(state.patch.bank_lsb),
+                program: parse_data_byte(&state.program, "Program")?
+                    .unwrap_or(state.patch.program)
+            };
+
+            programs.push((state.patch, to));
+        }
+
+        mappings.push(Mapping {
+            track: i,
+            map,
+            collisions,
+            unmapped,
+            controllers,
+            programs,
+            rules,
+            scale: track.scale.scale()?,
+            chords: track.chords.chords()?,
+            articulations,
+            quantize: track.quantize.quantize()?,
+            split: track.split.split()?,
+            dynamics: track.dynamics.dynamics()?,
+            retune: track.tuning.retune()?
         });
     }
+
+    Ok(mappings)
 }
 
-fn init_tracks(midi: &MidiFile) -> Vec<Vec<InputState>> {
+fn init_tracks(midi: &MidiFile) -> Vec<TrackState> {
     let len = midi.tracks.len();
     let mut tracks = Vec::with_capacity(len);
 
-    for track in &midi.tracks {
+    for (i, track) in midi.tracks.iter().enumerate() {
         let mut states = Vec::with_capacity(track.len());
 
         for note in track {
-            let state = InputState::new(*note);
-            states.push(state);
+            for channel in 0..16 {
+                if note.channels & (1 << channel) != 0 {
+                    states.push(InputState::new(note.note, channel));
+                }
+            }
         }
 
-        tracks.push(states);
+        let controllers = midi.controllers[i].iter()
+            .map(|x| ControllerState {
+                controller: *x,
+                map_to_dropdown: dropdown::State::with_selection(0),
+                thresholds: vec![ThresholdState::default()]
+            })
+            .collect();
+
+        let programs = midi.programs[i].iter()
+            .map(|x| ProgramState {
+                patch: *x,
+                bank_msb: ConstStr::new(),
+                bank_lsb: ConstStr::new(),
+                program: ConstStr::new()
+            })
+            .collect();
+
+        tracks.push(TrackState {
+            rules: Vec::new(),
+            scale: ScaleState::default(),
+            tuning: TuningState::default(),
+            quantize: QuantizeState::default(),
+            split: SplitState::default(),
+            dynamics: DynamicsState::default(),
+            chords: ChordsState::default(),
+            inputs: states,
+            controllers,
+            programs
+        });
     }
 
     tracks
 }
 
-impl InputState {
-    #[inline]
-    fn new(note: wmidi::Note) -> Self {
-        Self {
-            note,
+/// Draws the bands of the histogram as bars, scaled to the largest one.
+fn draw_histogram(ctx: &mut Context, histogram: &Histogram) {
+    let area = ctx.layout_next();
+    let largest = histogram.iter().copied().max().unwrap_or(0).max(1);
+    let width = area.w / histogram.len() as i32;
+    let color = ctx.style.colors[WidgetColor::ButtonHover];
+
+    ctx.draw_rect(area, ctx.style.colors[WidgetColor::Base]);
+
+    for (i, count) in histogram.iter().enumerate() {
+        let height = (count * area.h as usize / largest) as i32;
+
+        ctx.draw_rect(
+            rect(area.x + i as i32 * width, area.y + area.h - height, width - 1, height),
+            color
+        );
+    }
+}
+
+fn patch_label(patch: &Patch) -> String {
+    let bank = |x: Option<u8>| x.map_or("-".into(), |x| x.to_string());
+
+    format!(
+        "Bank {}:{} Program {}",
+        bank(patch.bank_msb),
+        bank(patch.bank_lsb),
+        patch.program
+    )
+}
+
+fn controller_options() -> Vec<String> {
+    let mut options = Vec::with_capacity(131);
+    options.push("Keep".into());
+    options.push("Drop".into());
+    options.push("Notes".into());
+
+    for i in 0..128 {
+        options.push(format!("CC {i}"));
+    }
+
+    options
+}
+
+impl TrackState {
+    /// Returns the rules that are filled in correctly.
+    fn draw_rules(&mut self, ctx: &mut Context, label_width: i32, edited: &mut bool) -> Vec<Rule> {
+        let mut removed: Option<usize> = None;
+
+        ctx.layout_row(&[-1], 0);
+        ctx.label("Rules:");
+
+        for (i, state) in self.rules.iter_mut().enumerate() {
+            ctx.push_id(&(state as *const RuleState));
+
+            ctx.layout_row(&[label_width, 100, 20], 0);
+            ctx.label("Rule:");
+            *edited |= ctx.w(Dropdown::new(&mut state.kind, &RULE_KINDS)).submit;
+
+            if ctx.w(
+                Button::icon(Icon::Close)
+                    .no_frame()
+                    .with_cursor()
+            ).submit {
+                removed = Some(i);
+                *edited = true;
+            }
+
+            match state.kind.index.unwrap_or(0) {
+                1 => {
+                    ctx.layout_row(&[label_width, 40, 40, 20, 40], 0);
+                    ctx.label("Notes:");
+                    *edited |= ctx.textbox(&mut state.first).change;
+                    *edited |= ctx.textbox(&mut state.second).change;
+                    ctx.label("to");
+                    *edited |= ctx.textbox(&mut state.third).change;
+                },
+                2 => {
+                    ctx.layout_row(&[label_width, 40, 20, 40], 0);
+                    ctx.label("Pitch:");
+                    *edited |= ctx.textbox(&mut state.first).change;
+                    ctx.label("to");
+                    *edited |= ctx.textbox(&mut state.second).change;
+                },
+                _ => {
+                    ctx.layout_row(&[label_width, 40], 0);
+                    ctx.label("Semitones:");
+                    *edited |= ctx.textbox(&mut state.first).change;
+                }
+            }
+
+            ctx.pop_id();
+        }
+
+        if let Some(i) = removed {
+            self.rules.remove(i);
+        }
+
+        ctx.layout_row(&[label_width, 100], 0);
+        ctx.label("");
+
+        ctx.push_id(&(self as *const TrackState));
+        if ctx.button("Add rule") {
+            self.rules.push(RuleState::default());
+            *edited = true;
+        }
+        ctx.pop_id();
+
+        self.rules.iter().filter_map(|x| x.rule().ok()).collect()
+    }
+}
+
+impl RuleState {
+    fn rule(&self) -> Result<Rule> {
+        let rule = match self.kind.index.unwrap_or(0) {
+            1 => {
+                let start = parse_note(&self.first, "Range start")?;
+                let end = parse_note(&self.second, "Range end")?;
+
+                if start > end {
+                    return Err(Error::Mapping(
+                        format!("The range {start} - {end} of a shift rule is empty.")
+                    ));
+                }
+
+                Rule::Shift {
+                    from: start..=end,
+                    to: parse_note(&self.third, "Shift target")?
+                }
+            },
+            2 => Rule::PitchClass {
+                from: parse_pitch_class(self.first.as_str())?,
+                to: parse_pitch_class(self.second.as_str())?
+            },
+            _ => Rule::Transpose(parse_field(&self.first, "Transpose semitones", 0)?)
+        };
+
+        Ok(rule)
+    }
+}
+
+impl Default for RuleState {
+    fn default() -> Self {
+        Self {
+            kind: dropdown::State::with_selection(0),
+            first: ConstStr::new(),
+            second: ConstStr::new(),
+            third: ConstStr::new()
+        }
+    }
+}
+
+impl ScaleState {
+    /// Returns the scale if it is filled in correctly.
+    fn draw(&mut self, ctx: &mut Context, label_width: i32, edited: &mut bool) -> Option<Scale> {
+        ctx.push_id(&(self as *const ScaleState));
+
+        ctx.layout_row(&[label_width, 100], 0);
+        ctx.label("Scale:");
+        *edited |= ctx.w(Dropdown::new(&mut self.mode, &SCALES).visible_items(10)).submit;
+
+        match self.mode.index.unwrap_or(0) {
+            0 => { },
+            index => {
+                if index == SCALES.len() - 1 {
+                    ctx.layout_row(&[label_width, -1], 0);
+                    ctx.label("Pitches:");
+                    *edited |= ctx.textbox(&mut self.custom).change;
+                } else {
+                    ctx.layout_row(&[label_width, 40], 0);
+                    ctx.label("Root:");
+                    *edited |= ctx.textbox(&mut self.root).change;
+                }
+
+                ctx.layout_row(&[label_width, 100], 0);
+                ctx.label("Round:");
+                *edited |= ctx.w(Dropdown::new(&mut self.rounding, &ROUNDINGS)).submit;
+            }
+        }
+
+        ctx.pop_id();
+
+        self.scale().ok().flatten()
+    }
+
+    fn scale(&self) -> Result<Option<Scale>> {
+        let pitch_classes = match self.mode.index.unwrap_or(0) {
+            0 => return Ok(None),
+            index if index == SCALES.len() - 1 => {
+                let mut mask = 0;
+
+                for name in self.custom.as_str().split([' ', ',']).filter(|x| !x.is_empty()) {
+                    mask |= 1 << parse_pitch_class(name)?;
+                }
+
+                mask
+            },
+            index => {
+                let root = match self.root.as_str().trim() {
+                    "" => 0,
+                    root => parse_pitch_class(root)?
+                };
+
+                SCALE_INTERVALS[index - 1]
+                    .iter()
+                    .fold(0, |mask, x| mask | 1 << ((root + x) % 12))
+            }
+        };
+
+        let rounding = match self.rounding.index.unwrap_or(0) {
+            1 => Rounding::Up,
+            2 => Rounding::Down,
+            _ => Rounding::Nearest
+        };
+
+        Ok(Some(Scale {
+            pitch_classes,
+            rounding
+        }))
+    }
+}
+
+impl Default for ScaleState {
+    fn default() -> Self {
+        Self {
+            mode: dropdown::State::with_selection(0),
+            root: ConstStr::new(),
+            rounding: dropdown::State::with_selection(0),
+            custom: ConstStr::new()
+        }
+    }
+}
+
+impl ChordsState {
+    /// Returns the index of the pattern and the selected output when
+    /// the output of a pattern changes.
+    fn draw<'a>(
+        &mut self,
+        ctx: &mut Context,
+        outputs: &'a [&'a str],
+        label_width: i32,
+        box_width: i32,
+        edited: &mut bool
+    ) -> Option<(usize, usize)> {
+        let mut selected: Option<(usize, usize)> = None;
+        let mut removed: Option<usize> = None;
+
+        ctx.push_id(&(self as *const ChordsState));
+
+        ctx.layout_row(&[-1], 0);
+        ctx.label("Chords:");
+
+        if !self.patterns.is_empty() {
+            ctx.layout_row(&[label_width, 60, 60], 0);
+            ctx.label("Within:");
+            *edited |= ctx.textbox(&mut self.tolerance).change;
+            *edited |= ctx.w(Dropdown::new(&mut self.unit, &SPAN_UNITS)).submit;
+        }
+
+        for (i, chord) in self.patterns.iter_mut().enumerate() {
+            ctx.push_id(&(chord as *const ChordState));
+
+            ctx.layout_row(&[label_width, 80, box_width, 20], 0);
+            ctx.label("Chord:");
+            *edited |= ctx.w(Dropdown::new(&mut chord.kind, &CHORD_KINDS)).submit;
+            *edited |= ctx.textbox(&mut chord.notes).change;
+
+            if ctx.w(
+                Button::icon(Icon::Close)
+                    .no_frame()
+                    .with_cursor()
+            ).submit {
+                removed = Some(i);
+                *edited = true;
+            }
+
+            ctx.layout_row(&[label_width, box_width], 0);
+            ctx.label("Play:");
+            *edited |= ctx.w(Dropdown::new(&mut chord.output, &CHORD_OUTPUTS)).submit;
+
+            ctx.layout_row(&[label_width, box_width], 0);
+
+            if chord.output.index == Some(1) {
+                ctx.label("Marker:");
+                *edited |= ctx.textbox(&mut chord.marker).change;
+            } else {
+                ctx.label("Output:");
+
+                if let Some(output) = chord.map_to.draw(ctx, outputs) {
+                    selected = Some((i, output));
+                }
+            }
+
+            ctx.pop_id();
+        }
+
+        if let Some(i) = removed {
+            self.patterns.remove(i);
+        }
+
+        ctx.layout_row(&[label_width, 100], 0);
+        ctx.label("");
+
+        if ctx.button("Add chord") {
+            self.patterns.push(ChordState::default());
+            *edited = true;
+        }
+
+        ctx.pop_id();
+
+        selected
+    }
+
+    /// Skips the patterns without notes or without an output.
+    fn chords(&self) -> Result<Option<Chords>> {
+        let mut patterns = Vec::with_capacity(self.patterns.len());
+
+        for state in &self.patterns {
+            let notes = parse_notes(state.notes.as_str(), "Chord notes")?;
+
+            if notes.is_empty() {
+                continue;
+            }
+
+            let notes = if state.kind.index == Some(1) {
+                ChordNotes::Intervals(notes)
+            } else if notes.len() < 2 {
+                return Err(Error::Mapping(
+                    format!("The chord \"{}\" needs at least two notes.", state.notes.as_str())
+                ));
+            } else {
+                ChordNotes::Keys(notes)
+            };
+
+            let output = if state.output.index == Some(1) {
+                ChordOutput::Root {
+                    marker: state.marker.as_str().trim().into()
+                }
+            } else {
+                match state.map_to.output {
+                    Some(output) => ChordOutput::Note(output),
+                    None => continue
+                }
+            };
+
+            patterns.push(Chord {
+                notes,
+                output
+            });
+        }
+
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Chords {
+            tolerance: parse_span(&self.tolerance, &self.unit, "Chord window")?,
+            patterns
+        }))
+    }
+}
+
+impl TuningState {
+    /// Returns the error when a file can not be read.
+    fn draw(
+        &mut self,
+        ctx: &mut Context,
+        label_width: i32,
+        box_width: i32,
+        edited: &mut bool
+    ) -> Option<Error> {
+        let mut error: Option<Error> = None;
+
+        ctx.push_id(&(self as *const TuningState));
+
+        let files = [
+            ("Tuning:", "Load Scala file...", "Scala", "scl", &mut self.scale),
+            ("Keys:", "Load keyboard map...", "Keyboard map", "kbm", &mut self.keyboard_map)
+        ];
+
+        for (i, (label, placeholder, filter, extension, file)) in files.into_iter().enumerate() {
+            ctx.layout_row(&[label_width, box_width, 20], 0);
+            ctx.label(label);
+
+            let text = file.as_ref().map_or(placeholder, |x| x.0.as_str());
+
+            if ctx.button(text) {
+                let path = FileDialog::new()
+                    .add_filter(filter, &[extension])
+                    .pick_file();
+
+                if let Some(path) = path {
+                    match fs::read_to_string(path.as_path()) {
+                        Ok(text) => {
+                            let name = path.file_name().map_or(
+                                String::new(),
+                                |x| x.to_string_lossy().into_owned()
+                            );
+
+                            *file = Some((name, text));
+                            *edited = true;
+                        },
+                        Err(err) => error = Some(Error::Io(err))
+                    }
+                }
+            }
+
+            if file.is_some() && ctx.w(
+                Button::icon(Icon::Close)
+                    .no_frame()
+                    .with_cursor()
+            ).submit {
+                *file = None;
+                *edited = true;
+            }
+
+            // The keyboard map is only shown once a scale is loaded.
+            if i == 0 && file.is_none() {
+                break;
+            }
+        }
+
+        if self.scale.is_some() {
+            ctx.layout_row(&[label_width, box_width], 0);
+            ctx.label("Retune:");
+            *edited |= ctx.w(Dropdown::new(&mut self.mode, &RETUNE_MODES)).submit;
+
+            if self.mode.index == Some(0) {
+                ctx.layout_row(&[label_width, 40, 40, 40], 0);
+                ctx.label("Bend:");
+                *edited |= ctx.textbox(&mut self.range).change;
+                *edited |= ctx.textbox(&mut self.first_channel).change;
+                *edited |= ctx.textbox(&mut self.last_channel).change;
+            }
+        }
+
+        ctx.pop_id();
+
+        error
+    }
+
+    fn retune(&self) -> Result<Option<Retune>> {
+        let Some((_, scale)) = &self.scale else {
+            return Ok(None);
+        };
+
+        let tuning = Tuning::from_scala(scale, self.keyboard_map.as_ref().map(|x| x.1.as_str()))?;
+
+        let mode = match self.mode.index.unwrap_or(0) {
+            1 => RetuneMode::Mts,
+            _ => {
+                let range: u8 = parse_field(&self.range, "Pitch bend range", 2)?;
+                let first: u8 = parse_field(&self.first_channel, "First channel", 2)?;
+                let last: u8 = parse_field(&self.last_channel, "Last channel", 16)?;
+
+                if !(1..=24).contains(&range) {
+                    return Err(Error::Mapping(
+                        format!("Pitch bend range {range} is not in the range of 1 - 24.")
+                    ));
+                }
+
+                if first == 0 || first > last || last > 16 {
+                    return Err(Error::Mapping(
+                        "Enter the pitch bend channels in the range of 1 - 16.".into()
+                    ));
+                }
+
+                RetuneMode::PitchBend {
+                    range,
+                    channels: first - 1..=last - 1
+                }
+            }
+        };
+
+        Ok(Some(Retune {
+            tuning,
+            mode
+        }))
+    }
+}
+
+impl Default for TuningState {
+    fn default() -> Self {
+        Self {
+            scale: None,
+            keyboard_map: None,
+            mode: dropdown::State::with_selection(0),
+            range: ConstStr::new(),
+            first_channel: ConstStr::new(),
+            last_channel: ConstStr::new()
+        }
+    }
+}
+
+impl SplitState {
+    fn draw(&mut self, ctx: &mut Context, label_width: i32, box_width: i32, edited: &mut bool) {
+        ctx.push_id(&(self as *const SplitState));
+
+        ctx.layout_row(&[label_width, box_width], 0);
+        ctx.label("Split:");
+        let enabled = self.enabled;
+        ctx.checkbox("Keyboard split", &mut self.enabled);
+        *edited |= enabled != self.enabled;
+
+        if self.enabled {
+            // The notes are split once they have been mapped.
+            ctx.layout_row(&[label_width, 40, -1], 0);
+            ctx.label("At:");
+            *edited |= ctx.textbox(&mut self.point).change;
+            ctx.label("Output key");
+
+            ctx.layout_row(&[label_width, 40, -1], 0);
+            ctx.label("Layer:");
+            *edited |= ctx.textbox(&mut self.layer).change;
+            ctx.label("Keys below");
+
+            ctx.layout_row(&[label_width, 40, 40], 0);
+            ctx.label("");
+            ctx.label("Ch.");
+            ctx.label("Semi.");
+
+            for (label, part) in [("Lower:", &mut self.lower), ("Upper:", &mut self.upper)] {
+                ctx.push_id(&(part as *const SplitPartState));
+                ctx.layout_row(&[label_width, 40, 40, -1], 0);
+                ctx.label(label);
+                *edited |= ctx.textbox(&mut part.channel).change;
+                *edited |= ctx.textbox(&mut part.transpose).change;
+                let new_track = part.new_track;
+                ctx.checkbox("New track", &mut part.new_track);
+                *edited |= new_track != part.new_track;
+                ctx.pop_id();
+            }
+        }
+
+        ctx.pop_id();
+    }
+
+    fn split(&self) -> Result<Option<Split>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        let point = parse_note(&self.point, "Split point")?;
+        let layer: u8 = parse_field(&self.layer, "Layer keys", 0)?;
+
+        if layer > point {
+            return Err(Error::Mapping(
+                "Layer keys: the upper part can not start below key 0.".into()
+            ));
+        }
+
+        // Empty when the split point is 0.
+        let lower = match point {
+            0 => RangeInclusive::new(1, 0),
+            point => 0..=point - 1
+        };
+
+        Ok(Some(Split {
+            lower: self.lower.part(lower, "Lower part")?,
+            upper: self.upper.part(point - layer..=127, "Upper part")?
+        }))
+    }
+}
+
+impl SplitPartState {
+    fn part(&self, keys: RangeInclusive<u8>, name: &str) -> Result<SplitPart> {
+        let channel: u8 = parse_field(&self.channel, name, 0)?;
+
+        if channel > 16 {
+            return Err(Error::Mapping(
+                format!("{name}: channel {channel} is not in the range of 1 - 16.")
+            ));
+        }
+
+        Ok(SplitPart {
+            keys,
+            channel: channel.checked_sub(1),
+            transpose: parse_field(&self.transpose, name, 0)?,
+            new_track: self.new_track
+        })
+    }
+}
+
+impl Default for SplitState {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            point: ConstStr::new(),
+            layer: ConstStr::new(),
+            lower: SplitPartState::default(),
+            upper: SplitPartState::default()
+        }
+    }
+}
+
+impl Default for SplitPartState {
+    fn default() -> Self {
+        Self {
+            channel: ConstStr::new(),
+            transpose: ConstStr::new(),
+            new_track: false
+        }
+    }
+}
+
+impl QuantizeState {
+    fn draw(&mut self, ctx: &mut Context, label_width: i32, edited: &mut bool) {
+        ctx.push_id(&(self as *const QuantizeState));
+
+        ctx.layout_row(&[label_width, 100], 0);
+        ctx.label("Quantize:");
+        *edited |= ctx.w(Dropdown::new(&mut self.grid, &QUANTIZE_GRIDS)).submit;
+
+        if self.grid.index.is_some_and(|x| x > 0) {
+            ctx.layout_row(&[label_width, 40, 50, 40, 60, 40], 0);
+            ctx.label("Strength %:");
+            *edited |= ctx.textbox(&mut self.strength).change;
+            ctx.label("Swing %:");
+            *edited |= ctx.textbox(&mut self.swing).change;
+            ctx.label("Window %:");
+            *edited |= ctx.textbox(&mut self.window).change;
+        }
+
+        ctx.pop_id();
+    }
+
+    fn quantize(&self) -> Result<Option<Quantize>> {
+        let division = QUANTIZE_DIVISIONS[self.grid.index.unwrap_or(0)];
+
+        if division == 0 {
+            return Ok(None);
+        }
+
+        let mut values = [0; 3];
+
+        for (value, (field, name, default)) in values.iter_mut().zip([
+            (&self.strength, "Quantize strength", 100),
+            (&self.swing, "Swing", 0),
+            (&self.window, "Quantize window", 100)
+        ]) {
+            *value = parse_field(field, name, default)?;
+
+            if *value > 100 {
+                return Err(Error::Mapping(
+                    format!("{name}: {value}% is not in the range of 0 - 100%.")
+                ));
+            }
+        }
+
+        Ok(Some(Quantize {
+            division,
+            strength: values[0],
+            swing: values[1],
+            window: values[2]
+        }))
+    }
+}
+
+impl Default for QuantizeState {
+    fn default() -> Self {
+        Self {
+            grid: dropdown::State::with_selection(0),
+            strength: ConstStr::new(),
+            swing: ConstStr::new(),
+            window: ConstStr::new()
+        }
+    }
+}
+
+impl DynamicsState {
+    fn draw(&mut self, ctx: &mut Context, label_width: i32, edited: &mut bool) {
+        ctx.push_id(&(self as *const DynamicsState));
+
+        ctx.layout_row(&[label_width, 100], 0);
+        ctx.label("Dynamics:");
+        *edited |= ctx.w(Dropdown::new(&mut self.mode, &DYNAMICS_MODES)).submit;
+
+        match self.mode.index.unwrap_or(0) {
+            1 => {
+                ctx.layout_row(&[label_width, 40, 40, 45, 40], 0);
+                ctx.label("Range:");
+                *edited |= ctx.textbox(&mut self.first).change;
+                *edited |= ctx.textbox(&mut self.second).change;
+                ctx.label("Median:");
+                *edited |= ctx.textbox(&mut self.median).change;
+            },
+            2 => {
+                ctx.layout_row(&[label_width, 40, 45, 40], 0);
+                ctx.label("Above:");
+                *edited |= ctx.textbox(&mut self.first).change;
+                ctx.label("Ratio:");
+                *edited |= ctx.textbox(&mut self.second).change;
+            },
+            _ => { }
+        }
+
+        ctx.pop_id();
+    }
+
+    fn dynamics(&self) -> Result<Option<Dynamics>> {
+        let dynamics = match self.mode.index.unwrap_or(0) {
+            1 => {
+                let low = parse_velocity(&self.first, 1)?;
+                let high = parse_velocity(&self.second, 127)?;
+                let median = parse_velocity(&self.median, 64)?;
+
+                if !(low..=high).contains(&median) {
+                    return Err(Error::Mapping(format!(
+                        "The median velocity {median} is not in the range of {low} - {high}."
+                    )));
+                }
+
+                Dynamics::Normalize {
+                    range: low..=high,
+                    median
+                }
+            },
+            2 => {
+                let ratio: f32 = parse_field(&self.second, "Compression ratio", 2.0)?;
+
+                if ratio < 1.0 {
+                    return Err(Error::Mapping("Compression ratio can not be below 1.".into()));
+                }
+
+                Dynamics::Compress {
+                    threshold: parse_velocity(&self.first, 64)?,
+                    ratio
+                }
+            },
+            _ => return Ok(None)
+        };
+
+        Ok(Some(dynamics))
+    }
+}
+
+impl Default for DynamicsState {
+    fn default() -> Self {
+        Self {
+            mode: dropdown::State::with_selection(0),
+            first: ConstStr::new(),
+            second: ConstStr::new(),
+            median: ConstStr::new()
+        }
+    }
+}
+
+impl Default for ChordsState {
+    fn default() -> Self {
+        Self {
+            tolerance: ConstStr::new(),
+            unit: dropdown::State::with_selection(0),
+            patterns: Vec::new()
+        }
+    }
+}
+
+impl Default for ChordState {
+    fn default() -> Self {
+        Self {
+            kind: dropdown::State::with_selection(0),
+            notes: ConstStr::new(),
+            output: dropdown::State::with_selection(0),
+            map_to: OutputSelect::default(),
+            marker: ConstStr::new()
+        }
+    }
+}
+
+impl InputState {
+    #[inline]
+    fn new(note: wmidi::Note, channel: u8) -> Self {
+        Self {
+            note,
+            channel,
             alias: ConstStr::new(),
-            map_to_dropdown: dropdown::State::with_selection(0),
-            map_to: None
+            zones: vec![ZoneState::default()],
+            articulations: Vec::new()
+        }
+    }
+
+    /// Returns the index of the articulation and the selected output when
+    /// the output of an articulation changes.
+    fn draw_articulations<'a>(
+        &mut self,
+        ctx: &mut Context,
+        outputs: &'a [&'a str],
+        label_width: i32,
+        box_width: i32,
+        edited: &mut bool
+    ) -> Option<(usize, usize)> {
+        let mut selected: Option<(usize, usize)> = None;
+        let mut removed: Option<usize> = None;
+
+        for (i, articulation) in self.articulations.iter_mut().enumerate() {
+            ctx.push_id(&(articulation as *const ArticulationState));
+
+            ctx.layout_row(&[label_width, 80, 20], 0);
+            ctx.label("Detect:");
+            *edited |= ctx.w(Dropdown::new(&mut articulation.rudiment, &RUDIMENTS)).submit;
+
+            if ctx.w(
+                Button::icon(Icon::Close)
+                    .no_frame()
+                    .with_cursor()
+            ).submit {
+                removed = Some(i);
+                *edited = true;
+            }
+
+            ctx.layout_row(&[label_width, 60, 60], 0);
+            ctx.label("Within:");
+            *edited |= ctx.textbox(&mut articulation.window).change;
+            *edited |= ctx.w(Dropdown::new(&mut articulation.unit, &SPAN_UNITS)).submit;
+
+            ctx.layout_row(&[label_width, box_width], 0);
+            ctx.label("");
+            let softer = articulation.softer;
+            ctx.checkbox("Softer grace notes", &mut articulation.softer);
+            *edited |= softer != articulation.softer;
+
+            ctx.layout_row(&[label_width, box_width], 0);
+            ctx.label("Play:");
+
+            if let Some(output) = articulation.map_to.draw(ctx, outputs) {
+                selected = Some((i, output));
+            }
+
+            ctx.pop_id();
+        }
+
+        if let Some(i) = removed {
+            self.articulations.remove(i);
+        }
+
+        ctx.layout_row(&[label_width, box_width], 0);
+        ctx.label("");
+
+        if ctx.button("Add flam or drag") {
+            self.articulations.push(ArticulationState::default());
+            *edited = true;
+        }
+
+        selected
+    }
+
+    /// Skips the articulations without an output.
+    fn articulations(&self) -> Result<Vec<Articulation>> {
+        let mut articulations = Vec::with_capacity(self.articulations.len());
+
+        for state in &self.articulations {
+            let Some(output) = state.map_to.output else {
+                continue;
+            };
+
+            articulations.push(Articulation {
+                key: note_key(self.channel, self.note as u8),
+                rudiment: match state.rudiment.index {
+                    Some(1) => Rudiment::Drag,
+                    _ => Rudiment::Flam
+                },
+                window: parse_span(&state.window, &state.unit, "Articulation window")?,
+                softer: state.softer,
+                output
+            });
+        }
+
+        Ok(articulations)
+    }
+}
+
+impl Default for ArticulationState {
+    fn default() -> Self {
+        Self {
+            rudiment: dropdown::State::with_selection(0),
+            window: ConstStr::new(),
+            // Rudiments are usually a few milliseconds apart.
+            unit: dropdown::State::with_selection(1),
+            softer: false,
+            map_to: OutputSelect::default()
+        }
+    }
+}
+
+impl ZoneState {
+    /// Returns the index of the layer, the index of the switch or pool
+    /// output if any and the selected output when an output changes.
+    fn draw<'a>(
+        &mut self,
+        ctx: &mut Context,
+        outputs: &'a [&'a str],
+        label_width: i32,
+        box_width: i32,
+        edited: &mut bool
+    ) -> Option<(usize, Option<usize>, usize)> {
+        let mut selected: Option<(usize, Option<usize>, usize)> = None;
+        let mut removed: Option<usize> = None;
+
+        for (layer, layer_state) in self.layers.iter_mut().enumerate() {
+            ctx.layout_row(&[label_width, box_width, 20], 0);
+            ctx.label(if layer == 0 { "Map to:" } else { "Layer:" });
+
+            ctx.push_id(&(layer_state as *const LayerState));
+            *edited |= ctx.w(Dropdown::new(&mut layer_state.kind, &TARGET_KINDS)).submit;
+
+            if layer > 0 && ctx.w(
+                Button::icon(Icon::Close)
+                    .no_frame()
+                    .with_cursor()
+            ).submit {
+                removed = Some(layer);
+                *edited = true;
+            }
+
+            match layer_state.kind.index.unwrap_or(0) {
+                1 => {
+                    ctx.layout_row(&[label_width, 40, label_width, 40], 0);
+                    ctx.label("CC:");
+                    *edited |= ctx.textbox(&mut layer_state.controller).change;
+                    ctx.label("Value:");
+                    *edited |= ctx.textbox(&mut layer_state.value).change;
+                },
+                2 => {
+                    ctx.layout_row(&[label_width, 40], 0);
+                    ctx.label("CC:");
+                    *edited |= ctx.textbox(&mut layer_state.controller).change;
+
+                    if let Some((switch, output)) = layer_state.draw_switches(
+                        ctx,
+                        outputs,
+                        label_width,
+                        box_width,
+                        edited
+                    ) {
+                        selected = Some((layer, Some(switch), output));
+                    }
+                },
+                3 => {
+                    if layer_state.strategy.index == Some(1) {
+                        ctx.layout_row(&[label_width, 80, 40, -1], 0);
+                        ctx.label("Order:");
+                        *edited |= ctx.w(
+                            Dropdown::new(&mut layer_state.strategy, &POOL_STRATEGIES)
+                        ).submit;
+                        ctx.label("Seed:");
+                        *edited |= ctx.textbox(&mut layer_state.seed).change;
+                    } else {
+                        ctx.layout_row(&[label_width, 80], 0);
+                        ctx.label("Order:");
+                        *edited |= ctx.w(
+                            Dropdown::new(&mut layer_state.strategy, &POOL_STRATEGIES)
+                        ).submit;
+                    }
+
+                    if let Some((entry, output)) = layer_state.draw_pool(
+                        ctx,
+                        outputs,
+                        label_width,
+                        box_width,
+                        edited
+                    ) {
+                        selected = Some((layer, Some(entry), output));
+                    }
+                },
+                _ => {
+                    ctx.layout_row(&[label_width, box_width], 0);
+                    ctx.label("");
+
+                    if let Some(output) = layer_state.map_to.draw(ctx, outputs) {
+                        selected = Some((layer, None, output));
+                    }
+                }
+            }
+
+            if layer_state.is_mapped() {
+                layer_state.velocity.draw(ctx, label_width, box_width, edited);
+
+                if layer_state.kind.index != Some(1) {
+                    layer_state.generator.draw(ctx, label_width, box_width, edited);
+                }
+            }
+            ctx.pop_id();
+        }
+
+        if let Some(layer) = removed {
+            self.layers.remove(layer);
+        }
+
+        ctx.layout_row(&[label_width, box_width], 0);
+        ctx.label("");
+
+        if ctx.button("Add layer") {
+            self.layers.push(LayerState::default());
+            *edited = true;
+        }
+
+        selected
+    }
+}
+
+impl Default for ZoneState {
+    fn default() -> Self {
+        Self {
+            min: ConstStr::new(),
+            max: ConstStr::new(),
+            layers: vec![LayerState::default()]
+        }
+    }
+}
+
+impl LayerState {
+    #[inline]
+    fn is_mapped(&self) -> bool {
+        match self.kind.index.unwrap_or(0) {
+            1 => true,
+            2 => self.switches.iter().any(|x| x.map_to.output.is_some()),
+            3 => self.pool.iter().any(|x| x.output.is_some()),
+            _ => self.map_to.output.is_some()
+        }
+    }
+
+    fn target_kind(&self) -> Result<Option<TargetKind>> {
+        let kind = match self.kind.index.unwrap_or(0) {
+            0 => return Ok(self.map_to.output.map(TargetKind::Note)),
+            3 => return self.pool_target(),
+            kind => kind
+        };
+
+        let Some(controller) = parse_data_byte(&self.controller, "Controller")? else {
+            return Err(Error::Mapping(format!(
+                "Enter a controller number for the {} layers.",
+                if kind == 1 { "controller" } else { "switch" }
+            )));
+        };
+
+        if kind == 1 {
+            return Ok(Some(TargetKind::Controller {
+                controller,
+                value: parse_data_byte(&self.value, "Controller value")?
+            }));
+        }
+
+        let mut outputs = Vec::with_capacity(self.switches.len());
+
+        for switch in &self.switches {
+            if let Some(output) = switch.map_to.output {
+                let from = parse_data_byte(&switch.from, "Switch value")?.unwrap_or(0);
+                outputs.push((from, output));
+            }
+        }
+
+        if outputs.is_empty() {
+            return Ok(None);
+        }
+
+        outputs.sort_by_key(|x| x.0);
+
+        Ok(Some(TargetKind::Switch {
+            controller,
+            outputs
+        }))
+    }
+
+    fn pool_target(&self) -> Result<Option<TargetKind>> {
+        let outputs: Vec<Output> = self.pool.iter().filter_map(|x| x.output).collect();
+
+        if outputs.is_empty() {
+            return Ok(None);
+        }
+
+        let strategy = match self.strategy.index {
+            Some(1) => PoolStrategy::Random(parse_field(&self.seed, "Random seed", 0)?),
+            _ => PoolStrategy::Cycle
+        };
+
+        Ok(Some(TargetKind::Pool {
+            outputs,
+            strategy
+        }))
+    }
+
+    /// Returns the index of the pool entry and the selected output when
+    /// the output of an entry changes.
+    fn draw_pool<'a>(
+        &mut self,
+        ctx: &mut Context,
+        outputs: &'a [&'a str],
+        label_width: i32,
+        box_width: i32,
+        edited: &mut bool
+    ) -> Option<(usize, usize)> {
+        let mut selected: Option<(usize, usize)> = None;
+        let mut removed: Option<usize> = None;
+
+        for (i, entry) in self.pool.iter_mut().enumerate() {
+            ctx.push_id(&(entry as *const OutputSelect));
+
+            ctx.layout_row(&[label_width, box_width, 20], 0);
+            ctx.label(if i == 0 { "Outputs:" } else { "" });
+
+            if let Some(output) = entry.draw(ctx, outputs) {
+                selected = Some((i, output));
+            }
+
+            if ctx.w(
+                Button::icon(Icon::Close)
+                    .no_frame()
+                    .with_cursor()
+            ).submit {
+                removed = Some(i);
+                *edited = true;
+            }
+
+            ctx.pop_id();
+        }
+
+        if let Some(i) = removed {
+            self.pool.remove(i);
+        }
+
+        ctx.layout_row(&[label_width, box_width], 0);
+        ctx.label("");
+
+        if ctx.button("Add pool output") {
+            self.pool.push(OutputSelect::default());
+            *edited = true;
+        }
+
+        selected
+    }
+
+    /// Returns the index of the switch output and the selected output when
+    /// the output of a switch changes.
+    fn draw_switches<'a>(
+        &mut self,
+        ctx: &mut Context,
+        outputs: &'a [&'a str],
+        label_width: i32,
+        box_width: i32,
+        edited: &mut bool
+    ) -> Option<(usize, usize)> {
+        let mut selected: Option<(usize, usize)> = None;
+        let mut removed: Option<usize> = None;
+
+        for (i, switch) in self.switches.iter_mut().enumerate() {
+            ctx.push_id(&(switch as *const SwitchState));
+
+            ctx.layout_row(&[label_width, 40, box_width - 40, 20], 0);
+            ctx.label("From:");
+            *edited |= ctx.textbox(&mut switch.from).change;
+
+            if let Some(output) = switch.map_to.draw(ctx, outputs) {
+                selected = Some((i, output));
+            }
+
+            if ctx.w(
+                Button::icon(Icon::Close)
+                    .no_frame()
+                    .with_cursor()
+            ).submit {
+                removed = Some(i);
+                *edited = true;
+            }
+
+            ctx.pop_id();
+        }
+
+        if let Some(i) = removed {
+            self.switches.remove(i);
+        }
+
+        ctx.layout_row(&[label_width, box_width], 0);
+        ctx.label("");
+
+        if ctx.button("Add switch output") {
+            self.switches.push(SwitchState::default());
+            *edited = true;
+        }
+
+        selected
+    }
+}
+
+impl Default for LayerState {
+    fn default() -> Self {
+        Self {
+            kind: dropdown::State::with_selection(0),
+            map_to: OutputSelect::default(),
+            controller: ConstStr::new(),
+            value: ConstStr::new(),
+            switches: vec![SwitchState::default()],
+            strategy: dropdown::State::with_selection(0),
+            seed: ConstStr::new(),
+            pool: vec![OutputSelect::default(), OutputSelect::default()],
+            velocity: VelocityState::default(),
+            generator: GeneratorState::default()
+        }
+    }
+}
+
+impl Default for SwitchState {
+    fn default() -> Self {
+        Self {
+            from: ConstStr::new(),
+            map_to: OutputSelect::default()
+        }
+    }
+}
+
+impl ControllerState {
+    /// Returns the index of the threshold and the selected output when
+    /// the output of a threshold changes.
+    fn draw_thresholds<'a>(
+        &mut self,
+        ctx: &mut Context,
+        outputs: &'a [&'a str],
+        label_width: i32,
+        box_width: i32,
+        edited: &mut bool
+    ) -> Option<(usize, usize)> {
+        let mut selected: Option<(usize, usize)> = None;
+        let mut removed: Option<usize> = None;
+
+        for (i, threshold) in self.thresholds.iter_mut().enumerate() {
+            ctx.push_id(&(threshold as *const ThresholdState));
+
+            ctx.layout_row(&[label_width, 40, 20], 0);
+            ctx.label("From:");
+            *edited |= ctx.textbox(&mut threshold.value).change;
+
+            if ctx.w(
+                Button::icon(Icon::Close)
+                    .no_frame()
+                    .with_cursor()
+            ).submit {
+                removed = Some(i);
+                *edited = true;
+            }
+
+            ctx.layout_row(&[label_width, box_width], 0);
+            ctx.label("Play:");
+
+            if let Some(output) = threshold.map_to.draw(ctx, outputs) {
+                selected = Some((i, output));
+            }
+
+            ctx.layout_row(&[label_width, 40], 0);
+            ctx.label("Velocity:");
+            *edited |= ctx.textbox(&mut threshold.velocity).change;
+
+            ctx.pop_id();
+        }
+
+        if let Some(i) = removed {
+            self.thresholds.remove(i);
+        }
+
+        ctx.layout_row(&[label_width, box_width], 0);
+        ctx.label("");
+
+        if ctx.button("Add threshold") {
+            self.thresholds.push(ThresholdState::default());
+            *edited = true;
+        }
+
+        selected
+    }
+}
+
+impl Default for ThresholdState {
+    fn default() -> Self {
+        Self {
+            value: ConstStr::new(),
+            map_to: OutputSelect::default(),
+            velocity: ConstStr::new()
+        }
+    }
+}
+
+impl OutputSelect {
+    /// Returns the index of the selected entry when the selection changes.
+    #[inline]
+    fn draw<'a>(&mut self, ctx: &mut Context, outputs: &'a [&'a str]) -> Option<usize> {
+        ctx.w(Dropdown::new(
+                &mut self.dropdown,
+                &outputs
+            ).visible_items(10)
+        ).submit.then(|| self.dropdown.index.unwrap())
+    }
+
+    fn reset(&mut self, note: wmidi::Note, removed_at: usize) {
+        if let Some(mapped) = self.output {
+            if mapped.note == note {
+                self.output = None;
+                self.dropdown.index = Some(0);
+            }
+        }
+
+        // The first entry is "None", so the removed output was at removed_at + 1.
+        let index = self.dropdown.index.as_mut().unwrap();
+        if *index > removed_at + 1 {
+            *index -= 1;
+        }
+    }
+}
+
+impl Default for OutputSelect {
+    fn default() -> Self {
+        Self {
+            dropdown: dropdown::State::with_selection(0),
+            output: None
+        }
+    }
+}
+
+impl Default for VelocityState {
+    fn default() -> Self {
+        Self {
+            mode: dropdown::State::with_selection(0),
+            first: ConstStr::new(),
+            second: ConstStr::new()
         }
     }
 }
@@ -432,3 +2546,311 @@ impl Default for TracksState {
         Self::Uninitialized
     }
 }
+
+impl MapWindowState {
+    /// Returns the index of the output selected as a fallback, if any.
+    fn draw_options<'a>(
+        &mut self,
+        ctx: &mut Context,
+        outputs: &'a [&'a str],
+        edited: &mut bool
+    ) -> Option<usize> {
+        let mut selected: Option<usize> = None;
+
+        ctx.layout_row(&[70, -1], 0);
+        ctx.label("Collisions:");
+        *edited |= ctx.w(Dropdown::new(&mut self.collisions, &COLLISION_POLICIES)).submit;
+
+        if self.collisions.index == Some(3) {
+            ctx.layout_row(&[70, 60, -1], 0);
+            ctx.label("Window:");
+            *edited |= ctx.textbox(&mut self.retrigger_window).change;
+            *edited |= ctx.w(Dropdown::new(&mut self.retrigger_unit, &SPAN_UNITS)).submit;
+        }
+
+        ctx.layout_row(&[70, -1], 0);
+        ctx.label("Unmapped:");
+        *edited |= ctx.w(Dropdown::new(&mut self.unmapped, &UNMAPPED_POLICIES)).submit;
+
+        if self.unmapped.index == Some(2) {
+            ctx.layout_row(&[70, -1], 0);
+            ctx.label("Fallback:");
+            selected = self.fallback.draw(ctx, outputs);
+        }
+
+        selected
+    }
+
+    fn unmapped_policy(&self) -> Result<UnmappedPolicy> {
+        let policy = match self.unmapped.index.unwrap_or(0) {
+            1 => UnmappedPolicy::Drop,
+            2 => match self.fallback.output {
+                Some(note) => UnmappedPolicy::Fallback(note),
+                None => return Err(Error::Mapping(
+                    "Select a fallback output for the unmapped notes.".into()
+                ))
+            },
+            _ => UnmappedPolicy::Keep
+        };
+
+        Ok(policy)
+    }
+
+    fn collision_policy(&self) -> Result<Option<CollisionPolicy>> {
+        let policy = match self.collisions.index.unwrap_or(0) {
+            1 => CollisionPolicy::KeepLoudest,
+            2 => CollisionPolicy::Merge,
+            3 => CollisionPolicy::Retrigger(
+                parse_span(&self.retrigger_window, &self.retrigger_unit, "Retrigger window")?
+            ),
+            _ => return Ok(None)
+        };
+
+        Ok(Some(policy))
+    }
+}
+
+impl GeneratorState {
+    fn draw(&mut self, ctx: &mut Context, label_width: i32, box_width: i32, edited: &mut bool) {
+        ctx.layout_row(&[label_width, box_width], 0);
+        ctx.label("Strokes:");
+        *edited |= ctx.w(Dropdown::new(&mut self.ornament, &ORNAMENTS)).submit;
+
+        if self.ornament.index.unwrap_or(0) == 0 {
+            return;
+        }
+
+        ctx.layout_row(&[label_width, 60, 60], 0);
+        ctx.label("Every:");
+        *edited |= ctx.textbox(&mut self.offset).change;
+        *edited |= ctx.w(Dropdown::new(&mut self.unit, &SPAN_UNITS)).submit;
+
+        ctx.layout_row(&[label_width, 50], 0);
+        ctx.label("Ratio:");
+        *edited |= ctx.textbox(&mut self.velocity).change;
+    }
+
+    fn generator(&self) -> Result<Option<Generator>> {
+        let ornament = match self.ornament.index.unwrap_or(0) {
+            1 => Ornament::Flam,
+            2 => Ornament::Drag,
+            3 => Ornament::Roll,
+            _ => return Ok(None)
+        };
+
+        let velocity: f32 = parse_field(&self.velocity, "Stroke velocity ratio", 0.5)?;
+
+        if velocity <= 0.0 {
+            return Err(Error::Mapping("Stroke velocity ratio must be greater than 0.".into()));
+        }
+
+        let offset = parse_span(&self.offset, &self.unit, "Stroke offset")?;
+
+        if offset == Span::Ticks(0) || offset == Span::Millis(0.0) {
+            return Err(Error::Mapping("Stroke offset must be greater than 0.".into()));
+        }
+
+        Ok(Some(Generator {
+            ornament,
+            offset,
+            velocity
+        }))
+    }
+}
+
+impl Default for GeneratorState {
+    fn default() -> Self {
+        Self {
+            ornament: dropdown::State::with_selection(0),
+            offset: ConstStr::new(),
+            unit: dropdown::State::with_selection(1),
+            velocity: ConstStr::new()
+        }
+    }
+}
+
+impl VelocityState {
+    fn draw(&mut self, ctx: &mut Context, label_width: i32, box_width: i32, edited: &mut bool) {
+        ctx.layout_row(&[label_width, box_width], 0);
+        ctx.label("Velocity:");
+        *edited |= ctx.w(Dropdown::new(&mut self.mode, &VELOCITY_MODES)).submit;
+
+        let labels = match self.mode.index.unwrap_or(0) {
+            1 => ["Scale:", "Offset:"],
+            2 => ["Min:", "Max:"],
+            3 => ["Power:", ""],
+            4 => ["Value:", ""],
+            _ => return
+        };
+
+        if labels[1].is_empty() {
+            ctx.layout_row(&[label_width, 50], 0);
+            ctx.label(labels[0]);
+            *edited |= ctx.textbox(&mut self.first).change;
+        } else {
+            ctx.layout_row(&[label_width, 50, label_width, 50], 0);
+            ctx.label(labels[0]);
+            *edited |= ctx.textbox(&mut self.first).change;
+            ctx.label(labels[1]);
+            *edited |= ctx.textbox(&mut self.second).change;
+        }
+    }
+
+    /// Empty fields fall back to values that leave the velocity unchanged.
+    fn transform(&self) -> Result<Option<VelocityTransform>> {
+        let transform = match self.mode.index.unwrap_or(0) {
+            1 => VelocityTransform::Linear {
+                scale: parse_field(&self.first, "Velocity scale", 1.0)?,
+                offset: parse_field(&self.second, "Velocity offset", 0)?
+            },
+            2 => {
+                let min = parse_velocity(&self.first, 1)?;
+                let max = parse_velocity(&self.second, 127)?;
+
+                if min > max {
+                    return Err(Error::Mapping(format!(
+                        "Velocity clamp: the minimum {min} is above the maximum {max}."
+                    )));
+                }
+
+                VelocityTransform::Clamp { min, max }
+            },
+            3 => {
+                let power: f32 = parse_field(&self.first, "Velocity curve power", 1.0)?;
+
+                if power <= 0.0 {
+                    return Err(Error::Mapping(
+                        "Velocity curve power must be greater than 0.".into()
+                    ));
+                }
+
+                VelocityTransform::Curve(power)
+            },
+            4 => VelocityTransform::Fixed(parse_velocity(&self.first, 127)?),
+            _ => return Ok(None)
+        };
+
+        Ok(Some(transform))
+    }
+}
+
+fn parse_field<T: FromStr, const N: usize>(
+    field: &ConstStr<N>,
+    name: &str,
+    default: T
+) -> Result<T> {
+    let text = field.as_str().trim();
+
+    if text.is_empty() {
+        return Ok(default);
+    }
+
+    text.parse().map_err(|_|
+        Error::Mapping(format!("{name}: \"{text}\" is not a valid value."))
+    )
+}
+
+fn parse_span<const N: usize>(
+    field: &ConstStr<N>,
+    unit: &dropdown::State,
+    name: &str
+) -> Result<Span> {
+    let span = if unit.index == Some(1) {
+        let ms: f64 = parse_field(field, name, 0.0)?;
+
+        if ms < 0.0 {
+            return Err(Error::Mapping(format!("{name} can not be negative.")));
+        }
+
+        Span::Millis(ms)
+    } else {
+        Span::Ticks(parse_field(field, name, 0)?)
+    };
+
+    Ok(span)
+}
+
+fn parse_data_byte<const N: usize>(field: &ConstStr<N>, name: &str) -> Result<Option<u8>> {
+    if field.as_str().trim().is_empty() {
+        return Ok(None);
+    }
+
+    let value: u8 = parse_field(field, name, 0)?;
+
+    if value > 127 {
+        return Err(Error::Mapping(format!("{name} {value} is not in the range of 0 - 127.")));
+    }
+
+    Ok(Some(value))
+}
+
+fn parse_note<const N: usize>(field: &ConstStr<N>, name: &str) -> Result<u8> {
+    match parse_data_byte(field, name)? {
+        Some(note) => Ok(note),
+        None => Err(Error::Mapping(format!("{name}: enter a note number in the range of 0 - 127.")))
+    }
+}
+
+/// Parses a list of note numbers (0 - 127) separated by spaces or commas.
+fn parse_notes(text: &str, name: &str) -> Result<Vec<u8>> {
+    let mut notes = Vec::new();
+
+    for note in text.split([' ', ',']).filter(|x| !x.is_empty()) {
+        match note.parse::<u8>() {
+            Ok(note) if note < 128 => notes.push(note),
+            _ => return Err(Error::Mapping(
+                format!("{name}: \"{note}\" is not a note number in the range of 0 - 127.")
+            ))
+        }
+    }
+
+    Ok(notes)
+}
+
+/// Accepts note names without an octave (C, C#, Db, ...) or numbers from 0 to 11.
+fn parse_pitch_class(text: &str) -> Result<u8> {
+    let text = text.trim();
+
+    if let Ok(class) = text.parse::<u8>() {
+        if class < 12 {
+            return Ok(class);
+        }
+    }
+
+    let mut chars = text.chars();
+
+    let base: Option<i8> = match chars.next().map(|x| x.to_ascii_uppercase()) {
+        Some('C') => Some(0),
+        Some('D') => Some(2),
+        Some('E') => Some(4),
+        Some('F') => Some(5),
+        Some('G') => Some(7),
+        Some('A') => Some(9),
+        Some('B') => Some(11),
+        _ => None
+    };
+
+    let accidental: Option<i8> = match chars.as_str() {
+        "" => Some(0),
+        "#" => Some(1),
+        "b" => Some(-1),
+        _ => None
+    };
+
+    match base.zip(accidental) {
+        Some((base, accidental)) => Ok((base + accidental).rem_euclid(12) as u8),
+        None => Err(Error::Mapping(
+            format!("\"{text}\" is not a pitch class, use C, C#, Db, ... or 0 - 11.")
+        ))
+    }
+}
+
+fn parse_velocity<const N: usize>(field: &ConstStr<N>, default: u8) -> Result<u8> {
+    let vel: u8 = parse_field(field, "Velocity", default)?;
+
+    if !(1..=127).contains(&vel) {
+        return Err(Error::Mapping(format!("Velocity {vel} is not in the range of 1 - 127.")));
+    }
+
+    Ok(vel)
+}
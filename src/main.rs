@@ -6,16 +6,18 @@ use std::{io, fmt::Display};
 
 use microui_femtovg::{App, Shell, run, microui::*};
 
-use midi_file::MidiFile;
+use midi_file::{MidiFile, Report};
 
 const ERR_POPUP_NAME: &str = "Error popup";
+const REPORT_POPUP_NAME: &str = "Mapping report";
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     Midly(midly::Error),
-    Io(io::Error)
+    Io(io::Error),
+    Mapping(String)
 }
 
 #[derive(Default)]
@@ -23,7 +25,8 @@ struct MidiMapper {
     midi: MidiFile,
     inputs: inputs::State,
     outputs: outputs::State,
-    error: Option<Error>
+    error: Option<Error>,
+    report: Option<Report>
 }
 
 fn main() {
@@ -57,8 +60,8 @@ impl App for MidiMapper {
                     match event {
                         inputs::Event::OutputSelected(selection) => {
                             if selection.output > 0 {
-                                let note = self.outputs.output(selection.output - 1);
-                                self.inputs.set_mapping(selection, Some(note));
+                                let output = self.outputs.output(selection.output - 1);
+                                self.inputs.set_mapping(selection, Some(output));
                             } else {
                                 self.inputs.set_mapping(selection, None);
                             }
@@ -67,11 +70,27 @@ impl App for MidiMapper {
                             self.midi = midi;
                         },
                         inputs::Event::Map { mappings, file } => {
-                            if let Err(err) = self.midi.map_and_save_file(&mappings, file) {
-                                self.error = Some(err)
+                            let result = self.outputs.settings().and_then(|settings|
+                                self.midi.map_and_save_file(&mappings, &settings, file)
+                            );
+
+                            match result {
+                                Ok(report) => self.report = Some(report),
+                                Err(err) => self.error = Some(err)
+                            }
+                        },
+                        inputs::Event::Preview(mappings) => {
+                            let result = self.outputs.settings().and_then(|settings|
+                                self.midi.preview(&mappings, &settings)
+                            );
+
+                            match result {
+                                Ok(report) => self.inputs.set_histograms(report.histograms),
+                                Err(err) => self.error = Some(err)
                             }
                         },
-                        inputs::Event::MidiLoadErr(err) => self.error = Some(err)
+                        inputs::Event::MidiLoadErr(err) |
+                        inputs::Event::MapErr(err) => self.error = Some(err)
                     }
                 }
                 ctx.layout_end_column();
@@ -81,6 +100,9 @@ impl App for MidiMapper {
                     match event {
                         outputs::Event::OutputRemoved { note, removed_index } => {
                             self.inputs.reset_mapping(note, removed_index);
+                        },
+                        outputs::Event::OutputChanged { index, output } => {
+                            self.inputs.update_output(index, output);
                         }
                     };
                 }
@@ -88,6 +110,7 @@ impl App for MidiMapper {
             });
         
         self.draw_err_popup(ctx);
+        self.draw_report_popup(ctx);
     }
 }
 
@@ -97,50 +120,68 @@ impl MidiMapper {
             return;
         };
 
-        if let Some(index) = ctx.container_index_by_name(
-            ERR_POPUP_NAME,
-            ContainerOptions::default()
-        ) {
-            const MSG_BOX_HEIGHT: i32 = 80;
-
-            ctx.bring_to_front(index);
-            ctx.container_mut(index).open = true;
-
-            let pos = ctx.mouse_pos();
-            let height = MSG_BOX_HEIGHT + ctx.style.size.x;
-
-            Window::new(ERR_POPUP_NAME, rect(pos.x, pos.y, 300, height))
-                .no_resize()
-                .no_close()
-                .show(ctx, |ctx|
-            {
-                ctx.layout_row(&[-1], MSG_BOX_HEIGHT);
-                Panel::new("Error message box")
-                    .no_frame()
-                    .show(ctx, |ctx|
-                {
-                    ctx.text(err.to_string());
-                });
-
-                ctx.layout_row(&[-1], 0);
-                if ctx.button("Ok") {
-                    ctx.container_mut(index).open = false;
-                }
-            });
+        if !draw_popup(ctx, ERR_POPUP_NAME, err.to_string()) {
+            self.error = None;
+        }
+    }
 
-            if !ctx.container(index).open {
-                self.error = None;
-            }
+    fn draw_report_popup(&mut self, ctx: &mut Context) {
+        let Some(report) = self.report.as_ref() else {
+            return;
+        };
+
+        if !draw_popup(ctx, REPORT_POPUP_NAME, report.to_string()) {
+            self.report = None;
         }
     }
 }
 
+/// Returns `false` once the popup has been closed.
+fn draw_popup(ctx: &mut Context, name: &str, text: String) -> bool {
+    let Some(index) = ctx.container_index_by_name(
+        name,
+        ContainerOptions::default()
+    ) else {
+        return true;
+    };
+
+    const MSG_BOX_HEIGHT: i32 = 80;
+
+    ctx.bring_to_front(index);
+    ctx.container_mut(index).open = true;
+
+    let pos = ctx.mouse_pos();
+    let height = MSG_BOX_HEIGHT + ctx.style.size.x;
+
+    Window::new(name, rect(pos.x, pos.y, 300, height))
+        .no_resize()
+        .no_close()
+        .show(ctx, |ctx|
+    {
+        ctx.layout_row(&[-1], MSG_BOX_HEIGHT);
+        Panel::new("Message box")
+            .no_frame()
+            .show(ctx, |ctx|
+        {
+            ctx.text(text);
+        });
+
+        ctx.layout_row(&[-1], 0);
+        if ctx.button("Ok") {
+            ctx.container_mut(index).open = false;
+        }
+    });
+
+    ctx.container(index).open
+}
+
 impl Display for Error {
     #[inline]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::Midly(err) => err.fmt(f),
-            Error::Io(err) => err.fmt(f)
+            Error::Io(err) => err.fmt(f),
+            Error::Mapping(msg) => msg.fmt(f)
         }
     }
 }
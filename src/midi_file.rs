@@ -1,92 +1,1109 @@
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    mem,
+    fmt::Display,
+    ops::RangeInclusive,
+    collections::{VecDeque, HashMap}
+};
 
-use midly::{Smf, TrackEventKind, MidiMessage, num::u7};
-use nohash_hasher::{IntSet, IntMap};
+use midly::{Smf, Format, Timing, TrackEventKind, MidiMessage, MetaMessage, num::{u4, u7}};
+use nohash_hasher::IntMap;
 
-use super::Result;
+use super::{Result, Error};
+
+mod events;
+mod tempo;
+mod collisions;
+mod chords;
+mod articulations;
+mod chokes;
+mod lengths;
+mod tuning;
+mod mpe;
+mod split;
+mod dynamics;
+mod quantize;
+
+#[cfg(test)]
+mod tests;
+
+pub use tempo::Span;
+pub use collisions::CollisionPolicy;
+pub use chords::{Chords, Chord, ChordNotes, ChordOutput};
+pub use articulations::{Articulation, Rudiment, Generator, Ornament};
+pub use chokes::{ChokeGroup, Choke};
+pub use lengths::NoteLength;
+pub use tuning::{Tuning, Retune, RetuneMode};
+pub use mpe::MpeZone;
+pub use split::{Split, SplitPart};
+pub use dynamics::{Dynamics, Histogram};
+pub use quantize::Quantize;
+
+use tempo::TempoMap;
+use events::{Events, NoteSpan};
 
 #[derive(Default, Debug)]
 pub struct MidiFile {
-    pub tracks: Vec<Vec<wmidi::Note>>,
+    pub tracks: Vec<Vec<TrackNote>>,
+    /// The controller numbers used on each track, in ascending order.
+    pub controllers: Vec<Vec<u8>>,
+    pub programs: Vec<Vec<Patch>>,
+    /// The zones of each track. The notes on the member channels of a zone
+    /// are listed on its manager channel.
+    pub mpe_zones: Vec<Vec<MpeZone>>,
     bytes: Vec<u8>
 }
 
+/// A program change along with the bank selected on its channel at the time.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Patch {
+    /// Bank select MSB (CC0), if one was sent.
+    pub bank_msb: Option<u8>,
+    /// Bank select LSB (CC32), if one was sent.
+    pub bank_lsb: Option<u8>,
+    pub program: u8
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TrackNote {
+    pub note: wmidi::Note,
+    /// Bit mask of the channels (0 - 15) that the note is played on.
+    pub channels: u16
+}
+
 #[derive(Debug)]
 pub struct Mapping {
     pub track: usize,
-    pub map: IntMap<u8, wmidi::Note>
+    /// Keyed by [`note_key`].
+    pub map: IntMap<u16, Vec<Zone>>,
+    pub collisions: Option<CollisionPolicy>,
+    pub unmapped: UnmappedPolicy,
+    pub controllers: IntMap<u8, ControllerTarget>,
+    /// Replaces the first patch of each pair with the second. The bank
+    /// selects of the old patch are dropped and new ones are only sent for
+    /// the parts of the new patch that are set.
+    pub programs: Vec<(Patch, Patch)>,
+    /// Applied to the notes that are not mapped explicitly, in the order
+    /// described by [`apply_rules`].
+    pub rules: Vec<Rule>,
+    /// Snaps the notes that are not mapped explicitly to the scale,
+    /// after the rules have been applied.
+    pub scale: Option<Scale>,
+    /// Collapses groups of notes before the rest of the mapping is applied.
+    /// The notes that replace them are not mapped any further.
+    pub chords: Option<Chords>,
+    /// Detected before the chords, the notes that replace the rudiments
+    /// are not mapped any further either.
+    pub articulations: Vec<Articulation>,
+    /// Moves the hits of the track towards the grid before anything else
+    /// is applied, so the strokes added by articulations and generators
+    /// keep their timing.
+    pub quantize: Option<Quantize>,
+    /// Splits the mapped notes of the track by their output keys.
+    pub split: Option<Split>,
+    /// Applied to the whole track once it has been split.
+    pub dynamics: Option<Dynamics>,
+    /// Applied to the whole track after every other step.
+    pub retune: Option<Retune>
+}
+
+#[derive(Clone, Debug)]
+pub enum Rule {
+    /// Moves every note by the given number of semitones.
+    Transpose(i8),
+    /// Moves the notes in the range so that its first note lands on `to`.
+    Shift {
+        from: RangeInclusive<u8>,
+        to: u8
+    },
+    /// Replaces a pitch class (0 - 11, starting at C) with another one in
+    /// every octave.
+    PitchClass {
+        from: u8,
+        to: u8
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ControllerTarget {
+    Controller(u8),
+    Drop,
+    /// Turns the controller into notes, see [`Threshold`].
+    Notes(Vec<Threshold>)
+}
+
+/// Plays the output note while the value of the controller is at or above
+/// the threshold. The NoteOn is sent when the value rises to the threshold
+/// and the NoteOff when it falls below it or the track ends.
+#[derive(Clone, Copy, Debug)]
+pub struct Threshold {
+    /// In the range of 1 - 127.
+    pub value: u8,
+    pub output: Output,
+    /// Uses the controller value when not set.
+    pub velocity: Option<u8>
+}
+
+/// What to do with the notes of a track that have no output.
+#[derive(Clone, Copy, Debug)]
+pub enum UnmappedPolicy {
+    Keep,
+    Drop,
+    Fallback(Output)
+}
+
+/// A summary of the changes made while saving.
+#[derive(Default, Debug)]
+pub struct Report {
+    pub collisions: usize,
+    pub chords: usize,
+    pub articulations: usize,
+    pub chokes: usize,
+    pub lengths: usize,
+    pub retuned: usize,
+    pub tracks_added: usize,
+    pub velocities: usize,
+    pub quantized: usize,
+    /// The velocities of each track with dynamics before and after they
+    /// were applied, including the tracks added by its split.
+    pub histograms: Vec<(usize, Histogram, Histogram)>
+}
+
+/// Options of the output notes that apply to every track.
+#[derive(Default, Debug)]
+pub struct OutputSettings {
+    pub chokes: Vec<ChokeGroup>,
+    /// The first entry that plays a note sets its length.
+    pub lengths: Vec<(Output, NoteLength)>
+}
+
+/// Routes the hits of an input note whose velocity falls in the given range.
+/// The zones of an input must cover all velocities without overlapping.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    pub velocity: RangeInclusive<u8>,
+    pub targets: Vec<Target>
+}
+
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub kind: TargetKind,
+    pub velocity: Option<VelocityTransform>,
+    /// Adds strokes around the hits on note outputs.
+    pub generator: Option<Generator>
+}
+
+#[derive(Clone, Debug)]
+pub enum TargetKind {
+    Note(Output),
+    /// Picks the output by the last value of the controller on the channel
+    /// of the hit, e.g. the hi-hat pedal (CC4). Each output is used from its
+    /// value up to the value of the next one, in ascending order. Values
+    /// below the first one play nothing and a controller that has not been
+    /// sent yet counts as 0. NoteOffs and aftertouch go to the output that
+    /// was picked for the hit.
+    Switch {
+        controller: u8,
+        outputs: Vec<(u8, Output)>
+    },
+    /// Picks one of the outputs for each hit, e.g. alternate samples.
+    /// NoteOffs and aftertouch go to the output that was picked for the hit.
+    Pool {
+        outputs: Vec<Output>,
+        strategy: PoolStrategy
+    },
+    /// Sends a controller message on each hit instead of a note. The value
+    /// is the transformed velocity of the hit unless a fixed one is set.
+    Controller {
+        controller: u8,
+        value: Option<u8>
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum PoolStrategy {
+    /// Plays the outputs in order, starting over after the last one.
+    Cycle,
+    /// Plays the outputs in a random order that is the same for every
+    /// save with the same seed.
+    Random(u64)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Output {
+    pub note: wmidi::Note,
+    /// Moves the note to this channel (0 - 15) when set.
+    pub channel: Option<u8>
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum VelocityTransform {
+    Linear {
+        scale: f32,
+        offset: i16
+    },
+    /// A maximum below the minimum is raised to it.
+    Clamp {
+        min: u8,
+        max: u8
+    },
+    /// Raises the normalized velocity to the given power. Values above 1.0
+    /// give an exponential curve, values below 1.0 a logarithmic one.
+    Curve(f32),
+    Fixed(u8)
 }
 
 impl MidiFile {
     pub fn new(bytes: Vec<u8>) -> Result<Self> {
         let midi = Smf::parse(&bytes)?;
-        let tracks = unique_notes(midi);
+        let mpe_zones = mpe::detect(&midi);
+        let tracks = unique_notes(&midi, &mpe_zones);
+        let controllers = unique_controllers(&midi);
+        let programs = unique_programs(&midi);
 
         Ok(Self {
             bytes,
-            tracks
+            tracks,
+            controllers,
+            programs,
+            mpe_zones
         })
     }
 
-    pub fn map_and_save_file(&self, mappings: &[Mapping], file: PathBuf) -> Result<()> {
+    /// The hits are quantized first, then the articulations and chords are
+    /// replaced before the notes are mapped. The mapped notes are split and
+    /// their dynamics applied, followed by the note lengths, choke groups,
+    /// collisions and retuning of each output.
+    pub fn map_and_save_file(
+        &self,
+        mappings: &[Mapping],
+        settings: &OutputSettings,
+        file: PathBuf
+    ) -> Result<Report> {
+        let (midi, report) = self.map(mappings, settings)?;
+        midi.save(file.as_path())?;
+
+        Ok(report)
+    }
+
+    /// Maps the file without saving it, to see what the mapping would change.
+    #[inline]
+    pub fn preview(&self, mappings: &[Mapping], settings: &OutputSettings) -> Result<Report> {
+        self.map(mappings, settings).map(|x| x.1)
+    }
+
+    fn map<'a>(
+        &'a self,
+        mappings: &'a [Mapping],
+        settings: &OutputSettings
+    ) -> Result<(Smf<'a>, Report)> {
+        for mapping in mappings {
+            mapping.validate()?;
+        }
+
         let mut midi = Smf::parse(&self.bytes)?;
+        let tempo = TempoMap::new(&midi);
+        let mut report = Report::default();
+
+        // The tracks added by splits, appended once every track is mapped.
+        let mut added = Vec::new();
 
         for mapping in mappings {
-            for event in &mut midi.tracks[mapping.track] {
-                match &mut event.kind {
-                    TrackEventKind::Midi { message, .. } => {
-                        match message {
-                            MidiMessage::NoteOn { key, .. } => {
-                                if let Some(to) = mapping.map.get(&key.as_int()) {
-                                    *key = u7::from_int_lossy(*to as u8);
-                                }
-                            },
-                            MidiMessage::NoteOff { key, .. } => {
-                                if let Some(to) = mapping.map.get(&key.as_int()) {
-                                    *key = u7::from_int_lossy(*to as u8);
-                                }
+            let zones = &self.mpe_zones[mapping.track];
+            let track = mem::take(&mut midi.tracks[mapping.track]);
+            let mut events = events::to_absolute(track);
+            let mut replaced = Vec::new();
+
+            if let Some(quantize) = &mapping.quantize {
+                let Timing::Metrical(tpb) = midi.header.timing else {
+                    return Err(Error::Mapping(
+                        "Quantizing needs a file with metrical timing.".into()
+                    ));
+                };
+
+                report.quantized += quantize::apply(&mut events, quantize, tpb.as_int(), zones);
+            }
+
+            report.articulations += articulations::detect(
+                &mut events,
+                &mapping.articulations,
+                &tempo,
+                zones,
+                &mut replaced
+            );
+
+            if let Some(chords) = &mapping.chords {
+                report.chords +=
+                    chords::collapse(&mut events, chords, &tempo, zones, &mut replaced);
+            }
+
+            let mut events = map_track(events, mapping, &tempo, zones);
+            events::merge(&mut events, replaced);
+
+            let mut outputs = match &mapping.split {
+                Some(split) => split::apply(&mut events, split),
+                None => Vec::new()
+            };
+
+            outputs.insert(0, events);
+
+            if let Some(dynamics) = &mapping.dynamics {
+                let (mut before, mut after) = ([0; 16], [0; 16]);
+
+                for events in &mut outputs {
+                    add_histogram(&mut before, dynamics::histogram(events));
+                    report.velocities += dynamics::apply(events, dynamics);
+                    add_histogram(&mut after, dynamics::histogram(events));
+                }
+
+                report.histograms.push((mapping.track, before, after));
+            }
+
+            for events in &mut outputs {
+                report.lengths += lengths::apply(events, &settings.lengths, &tempo);
+                report.chokes += chokes::insert(events, &settings.chokes);
+
+                if let Some(policy) = mapping.collisions {
+                    report.collisions += collisions::resolve(events, policy, &tempo);
+                }
+
+                if let Some(retune) = &mapping.retune {
+                    report.retuned += tuning::apply(events, retune);
+                }
+            }
+
+            let mut outputs = outputs.into_iter();
+            midi.tracks[mapping.track] = events::to_track(outputs.next().unwrap());
+
+            for mut events in outputs {
+                let end = events.last().map_or(0, |x| x.0);
+                events.push((end, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+
+                added.push(events::to_track(events));
+            }
+        }
+
+        if !added.is_empty() {
+            match midi.header.format {
+                Format::Sequential => return Err(Error::Mapping(
+                    "Splits can only add tracks to files with parallel tracks.".into()
+                )),
+                Format::SingleTrack => midi.header.format = Format::Parallel,
+                Format::Parallel => { }
+            }
+
+            report.tracks_added = added.len();
+            midi.tracks.extend(added);
+        }
+
+        Ok((midi, report))
+    }
+}
+
+impl Mapping {
+    /// Checks that the velocity zones of every input cover the range of
+    /// 1 - 127 without overlapping or leaving gaps.
+    pub fn validate(&self) -> Result<()> {
+        for (key, zones) in &self.map {
+            let mut ranges: Vec<&RangeInclusive<u8>> = zones.iter()
+                .map(|x| &x.velocity)
+                .collect();
+            ranges.sort_by_key(|x| *x.start());
+
+            let mut next = 1u16;
+            let mut problem: Option<&str> = None;
+
+            for range in ranges {
+                let (start, end) = (*range.start() as u16, *range.end() as u16);
+
+                if start > end {
+                    problem = Some("have an empty range");
+                } else if start > next {
+                    problem = Some("leave a gap");
+                } else if start < next {
+                    problem = Some("overlap");
+                }
+
+                if problem.is_some() {
+                    break;
+                }
+
+                next = end + 1;
+            }
+
+            if problem.is_none() && next != 128 {
+                problem = Some("do not reach 127");
+            }
+
+            if let Some(problem) = problem {
+                let note = wmidi::Note::from_u8_lossy((key & 0x7f) as u8);
+
+                return Err(Error::Mapping(format!(
+                    "Track {}: velocity zones of {note} on channel {} {problem}.",
+                    self.track + 1,
+                    (key >> 7) + 1
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn zone(&self, key: u16, vel: u8) -> Option<usize> {
+        self.map.get(&key)?
+            .iter()
+            .position(|x| x.velocity.contains(&vel))
+    }
+}
+
+/// Rewrites the notes of the track according to the mapping. The velocity
+/// zone is picked by the velocity of each NoteOn and the matching NoteOff is
+/// sent to the same outputs. Polyphonic aftertouch follows the zone of the
+/// note it applies to. Notes mapped to more than one output are layered by
+/// inserting extra events on the same tick, so the timing stays the same.
+/// The notes on the member channels of an MPE zone are mapped like the notes
+/// of its manager channel but keep their channel, so that their pitch bend,
+/// pressure and timbre stay with them. The controllers on member channels
+/// are not mapped for the same reason.
+fn map_track<'a>(
+    events: Events<'a>,
+    mapping: &Mapping,
+    tempo: &TempoMap,
+    zones: &[MpeZone]
+) -> Events<'a> {
+    let mut result = Vec::with_capacity(events.len());
+    // The strokes added by generators, which can come before the hit.
+    let mut generated = Vec::new();
+    // The zones of the currently sounding notes, in the order they were hit.
+    // Keyed by the channel the note is played on.
+    let mut active: IntMap<u16, VecDeque<Hit>> = IntMap::default();
+    // The zone of the last hit of each note, used for aftertouch that
+    // comes after the note has ended, e.g. cymbal chokes.
+    let mut last: IntMap<u16, Hit> = IntMap::default();
+    let mut banks = Banks::default();
+    // The indices of the bank selects kept since the last program change
+    // on each channel, which a remapped program replaces.
+    let mut bank_selects: [Vec<usize>; 16] = Default::default();
+    let mut removed = Vec::new();
+    // The last value of each controller, keyed by [`note_key`].
+    let mut controller_values: IntMap<u16, u8> = IntMap::default();
+    let mut pools = Pools::default();
+    let mut end = 0;
+
+    for (tick, kind) in events {
+        end = tick;
+
+        let TrackEventKind::Midi { channel, message } = kind else {
+            result.push((tick, kind));
+            continue;
+        };
+
+        let instrument = mpe::instrument(zones, channel.as_int());
+        let is_member = instrument != channel.as_int();
+
+        let hit = match message {
+            MidiMessage::NoteOn { key: note, vel } if vel > 0 => {
+                let key = note_key(instrument, note.as_int());
+                let hit = mapping.zone(key, vel.as_int()).map(|zone| {
+                    let targets = &mapping.map[&key][zone].targets;
+                    let mut outputs = Vec::with_capacity(targets.len());
+
+                    for (i, target) in targets.iter().enumerate() {
+                        let (value, index) = match &target.kind {
+                            TargetKind::Switch { controller, .. } => {
+                                let controller = note_key(channel.as_int(), *controller);
+                                (controller_values.get(&controller).copied().unwrap_or(0), 0)
                             },
-                            _ => { }
+                            TargetKind::Pool { outputs, strategy } =>
+                                (0, pools.pick((key, zone, i), outputs.len(), *strategy)),
+                            _ => (0, 0)
+                        };
+
+                        outputs.push(target.kind.output(value, index));
+                    }
+
+                    Hit {
+                        zone,
+                        start: tick,
+                        vel: vel.as_int(),
+                        outputs
+                    }
+                });
+
+                if let Some(hit) = &hit {
+                    let voice = note_key(channel.as_int(), note.as_int());
+                    active.entry(voice).or_default().push_back(hit.clone());
+                    last.insert(voice, hit.clone());
+                }
+
+                hit.map(|x| (key, x))
+            },
+            MidiMessage::Aftertouch { key: note, .. } => {
+                let key = note_key(instrument, note.as_int());
+                let voice = note_key(channel.as_int(), note.as_int());
+                let hit = active.get(&voice)
+                    .and_then(|x| x.front().cloned())
+                    .or_else(|| last.get(&voice).cloned())
+                    .or_else(|| mapping.map.contains_key(&key).then(Hit::default));
+
+                hit.map(|x| (key, x))
+            },
+            MidiMessage::ProgramChange { program } => {
+                let patch = banks.patch(channel.as_int(), program.as_int());
+                let to = mapping.programs.iter().find(|x| x.0 == patch);
+                let kept = mem::take(&mut bank_selects[channel.as_int() as usize]);
+
+                if let Some((_, to)) = to {
+                    removed.extend(kept);
+
+                    let selects = [(0, to.bank_msb), (32, to.bank_lsb)];
+
+                    for (controller, value) in selects {
+                        if let Some(value) = value {
+                            let message = MidiMessage::Controller {
+                                controller: u7::new(controller),
+                                value: u7::from_int_lossy(value)
+                            };
+
+                            result.push((tick, TrackEventKind::Midi { channel, message }));
+                        }
+                    }
+
+                    let message = MidiMessage::ProgramChange {
+                        program: u7::from_int_lossy(to.program)
+                    };
+
+                    result.push((tick, TrackEventKind::Midi { channel, message }));
+                } else {
+                    result.push((tick, kind));
+                }
+
+                continue;
+            },
+            MidiMessage::Controller { controller, value } => {
+                banks.update(channel.as_int(), controller.as_int(), value.as_int());
+
+                let prev = controller_values
+                    .insert(note_key(channel.as_int(), controller.as_int()), value.as_int())
+                    .unwrap_or(0);
+
+                let target = match is_member {
+                    true => None,
+                    false => mapping.controllers.get(&controller.as_int())
+                };
+
+                match target {
+                    Some(ControllerTarget::Controller(to)) => {
+                        let message = MidiMessage::Controller {
+                            controller: u7::from_int_lossy(*to),
+                            value
+                        };
+
+                        result.push((tick, TrackEventKind::Midi { channel, message }));
+                    },
+                    Some(ControllerTarget::Drop) => { },
+                    Some(ControllerTarget::Notes(thresholds)) => {
+                        let value = value.as_int();
+
+                        for threshold in thresholds {
+                            let (was_on, is_on) = (prev >= threshold.value, value >= threshold.value);
+
+                            let message = match (was_on, is_on) {
+                                (false, true) => MidiMessage::NoteOn {
+                                    key: u7::new(0),
+                                    vel: u7::from_int_lossy(threshold.velocity.unwrap_or(value))
+                                },
+                                (true, false) => MidiMessage::NoteOff {
+                                    key: u7::new(0),
+                                    vel: u7::new(0)
+                                },
+                                _ => continue
+                            };
+
+                            result.push((tick, threshold.output.apply(channel, message)));
                         }
                     },
+                    None => {
+                        if matches!(controller.as_int(), 0 | 32) {
+                            bank_selects[channel.as_int() as usize].push(result.len());
+                        }
+
+                        result.push((tick, kind));
+                    }
+                }
+
+                continue;
+            },
+            MidiMessage::NoteOn { key: note, .. } | MidiMessage::NoteOff { key: note, .. } => {
+                let key = note_key(instrument, note.as_int());
+                let voice = note_key(channel.as_int(), note.as_int());
+                let hit = active.get_mut(&voice)
+                    .and_then(|x| x.pop_front())
+                    .or_else(|| mapping.map.contains_key(&key).then(Hit::default));
+
+                hit.map(|x| (key, x))
+            },
+            _ => {
+                result.push((tick, kind));
+                continue;
+            }
+        };
+
+        let targets = hit.as_ref()
+            .map_or(&[][..], |(key, hit)| &mapping.map[key][hit.zone].targets);
+        let picked = hit.as_ref().map_or(&[][..], |(_, hit)| &hit.outputs);
+
+        if targets.is_empty() {
+            let rule = match message {
+                MidiMessage::NoteOn { key, .. } |
+                MidiMessage::NoteOff { key, .. } |
+                MidiMessage::Aftertouch { key, .. } =>
+                    apply_rules(&mapping.rules, mapping.scale.as_ref(), key.as_int()),
+                _ => None
+            };
+
+            if let Some(note) = rule {
+                let message = with_key(message, u7::new(note));
+                result.push((tick, TrackEventKind::Midi { channel, message }));
+
+                continue;
+            }
+
+            match mapping.unmapped {
+                UnmappedPolicy::Keep => result.push((tick, kind)),
+                UnmappedPolicy::Drop => { },
+                UnmappedPolicy::Fallback(output) =>
+                    result.push((tick, output.keep_member(is_member).apply(channel, message)))
+            }
+
+            continue;
+        }
+
+        for (i, to) in targets.iter().enumerate() {
+            // A NoteOn with a velocity of 0 is a NoteOff.
+            let vel = match message {
+                MidiMessage::NoteOn { vel, .. } if vel > 0 => {
+                    let vel = vel.as_int();
+                    Some(to.velocity.map_or(vel, |x| x.apply(vel)))
+                },
+                _ => None
+            };
+
+            let output = match &to.kind {
+                TargetKind::Controller { controller, value } => {
+                    // Only the hits are sent, NoteOffs and aftertouch have no equivalent.
+                    let Some(vel) = vel else {
+                        continue;
+                    };
+
+                    let message = MidiMessage::Controller {
+                        controller: u7::from_int_lossy(*controller),
+                        value: u7::from_int_lossy(value.unwrap_or(vel))
+                    };
+
+                    result.push((tick, TrackEventKind::Midi { channel, message }));
+
+                    continue;
+                },
+                // NoteOffs without a hit use the output for a controller
+                // value of 0 or the first output of a pool.
+                kind => match picked.get(i).copied().unwrap_or_else(|| kind.output(0, 0)) {
+                    Some(output) => output.keep_member(is_member),
+                    None => continue
+                }
+            };
+
+            let mut kind = output.apply(channel, message);
+
+            if let (
+                Some(new_vel),
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. }
+            ) = (vel, &mut kind) {
+                *vel = u7::new(new_vel);
+            }
+
+            if let Some(generator) = &to.generator {
+                let (channel, key) = (
+                    output.channel.unwrap_or(channel.as_int()),
+                    output.note as u8
+                );
+
+                match (vel, hit.as_ref()) {
+                    (Some(vel), _) =>
+                        generator.grace_notes(tick, vel, channel, key, tempo, &mut generated),
+                    (None, Some((_, hit))) if hit.vel > 0 && events::is_note_off(&kind) => {
+                        let vel = to.velocity.map_or(hit.vel, |x| x.apply(hit.vel));
+                        generator.roll(hit.start, tick, vel, channel, key, tempo, &mut generated);
+                    },
                     _ => { }
                 }
             }
+
+            result.push((tick, kind));
         }
+    }
 
-        midi.save(file.as_path())?;
+    // Ends the notes of the controllers that are still above their thresholds.
+    let mut open: Vec<(u16, u8)> = controller_values.into_iter().collect();
+    open.sort_unstable();
 
-        Ok(())
+    for (key, value) in open {
+        let (channel, controller) = ((key >> 7) as u8, (key & 0x7f) as u8);
+
+        if mpe::instrument(zones, channel) != channel {
+            continue;
+        }
+
+        let Some(ControllerTarget::Notes(thresholds)) = mapping.controllers.get(&controller) else {
+            continue;
+        };
+
+        let (channel, message) = (
+            u4::new(channel),
+            MidiMessage::NoteOff { key: u7::new(0), vel: u7::new(0) }
+        );
+
+        for threshold in thresholds.iter().filter(|x| value >= x.value) {
+            result.push((end, threshold.output.apply(channel, message)));
+        }
+    }
+
+    if !removed.is_empty() {
+        let mut marked = vec![false; result.len()];
+
+        for i in removed {
+            marked[i] = true;
+        }
+
+        events::remove_marked(&mut result, &marked);
+    }
+
+    events::merge(&mut result, generated);
+
+    result
+}
+
+/// The zone a note was mapped to when it was hit.
+#[derive(Clone, Default)]
+struct Hit {
+    zone: usize,
+    start: u64,
+    vel: u8,
+    /// The output picked for each target of the zone.
+    outputs: Vec<Option<Output>>
+}
+
+/// The rotation of each pool target, keyed by the input note, zone and
+/// target.
+#[derive(Default)]
+struct Pools(HashMap<(u16, usize, usize), u64>);
+
+impl Pools {
+    fn pick(&mut self, target: (u16, usize, usize), len: usize, strategy: PoolStrategy) -> usize {
+        if len == 0 {
+            return 0;
+        }
+
+        match strategy {
+            PoolStrategy::Cycle => {
+                let next = self.0.entry(target).or_insert(0);
+                let index = *next as usize % len;
+                *next += 1;
+
+                index
+            },
+            PoolStrategy::Random(seed) => {
+                // SplitMix64
+                let state = self.0.entry(target).or_insert(seed);
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+
+                (z % len as u64) as usize
+            }
+        }
+    }
+}
+
+/// Tracks the bank selected on each channel.
+#[derive(Default)]
+struct Banks([(Option<u8>, Option<u8>); 16]);
+
+impl Banks {
+    #[inline]
+    fn update(&mut self, channel: u8, controller: u8, value: u8) {
+        match controller {
+            0 => self.0[channel as usize].0 = Some(value),
+            32 => self.0[channel as usize].1 = Some(value),
+            _ => { }
+        }
+    }
+
+    #[inline]
+    fn patch(&self, channel: u8, program: u8) -> Patch {
+        let (bank_msb, bank_lsb) = self.0[channel as usize];
+
+        Patch {
+            bank_msb,
+            bank_lsb,
+            program
+        }
+    }
+}
+
+impl TargetKind {
+    /// The output of a hit, given the value of the switch controller and
+    /// the index picked from the pool.
+    fn output(&self, value: u8, index: usize) -> Option<Output> {
+        match self {
+            Self::Note(output) => Some(*output),
+            Self::Switch { outputs, .. } =>
+                outputs.iter().rev().find(|x| x.0 <= value).map(|x| x.1),
+            Self::Pool { outputs, .. } => outputs.get(index).copied(),
+            Self::Controller { .. } => None
+        }
+    }
+}
+
+impl Output {
+    /// Whether the note is played on the output. Outputs without a channel
+    /// match the note on any channel.
+    #[inline]
+    fn plays(&self, note: &NoteSpan) -> bool {
+        note.key == self.note as u8 && self.channel.map_or(true, |x| x == note.channel)
+    }
+
+    /// Keeps notes on the member channel of an MPE zone.
+    #[inline]
+    fn keep_member(self, is_member: bool) -> Self {
+        match is_member {
+            true => Self { channel: None, ..self },
+            false => self
+        }
+    }
+
+    /// Moves the note or aftertouch message to the key and channel of the output.
+    fn apply<'a>(&self, channel: u4, message: MidiMessage) -> TrackEventKind<'a> {
+        TrackEventKind::Midi {
+            channel: self.channel.map_or(channel, u4::from_int_lossy),
+            message: with_key(message, u7::from_int_lossy(self.note as u8))
+        }
+    }
+}
+
+impl Scale {
+    pub fn snap(&self, note: u8) -> u8 {
+        if self.pitch_classes & 0xfff == 0 {
+            return note;
+        }
+
+        let in_scale = |note: i16| {
+            (0..=127).contains(&note) &&
+                self.pitch_classes & (1 << (note % 12)) != 0
+        };
+
+        let note = note as i16;
+        let up = (0..12).map(|x| note + x).find(|x| in_scale(*x));
+        let down = (0..12).map(|x| note - x).find(|x| in_scale(*x));
+
+        let snapped = match (self.rounding, up, down) {
+            (Rounding::Up, Some(up), _) => up,
+            (Rounding::Down, _, Some(down)) => down,
+            (Rounding::Nearest, Some(up), Some(down)) =>
+                if up - note < note - down { up } else { down },
+            // Either direction runs out of the MIDI range at the edges.
+            (_, up, down) => up.or(down).unwrap_or(note)
+        };
+
+        snapped as u8
     }
 }
 
-fn unique_notes<'a>(midi: Smf<'a>) -> Vec<Vec<wmidi::Note>> {
+impl VelocityTransform {
+    /// Returns the transformed velocity in the range of 1 - 127.
+    pub fn apply(&self, vel: u8) -> u8 {
+        let result = match *self {
+            Self::Linear { scale, offset } =>
+                (vel as f32 * scale).round() as i32 + offset as i32,
+            Self::Clamp { min, max } => vel.clamp(min, max.max(min)) as i32,
+            Self::Curve(exponent) =>
+                ((vel as f32 / 127.0).powf(exponent) * 127.0).round() as i32,
+            Self::Fixed(value) => value as i32
+        };
+
+        result.clamp(1, 127) as u8
+    }
+}
+
+#[inline]
+fn with_key(message: MidiMessage, key: u7) -> MidiMessage {
+    match message {
+        MidiMessage::NoteOn { vel, .. } => MidiMessage::NoteOn { key, vel },
+        MidiMessage::NoteOff { vel, .. } => MidiMessage::NoteOff { key, vel },
+        MidiMessage::Aftertouch { vel, .. } => MidiMessage::Aftertouch { key, vel },
+        other => other
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "The mapped file was saved.")?;
+        writeln!(f, "Collisions fixed: {}", self.collisions)?;
+        writeln!(f, "Chords collapsed: {}", self.chords)?;
+        writeln!(f, "Articulations detected: {}", self.articulations)?;
+        writeln!(f, "Notes choked: {}", self.chokes)?;
+        writeln!(f, "Note lengths changed: {}", self.lengths)?;
+        writeln!(f, "Notes retuned: {}", self.retuned)?;
+        writeln!(f, "Velocities changed: {}", self.velocities)?;
+        writeln!(f, "Hits quantized: {}", self.quantized)?;
+        write!(f, "Tracks added: {}", self.tracks_added)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Scale {
+    /// Bit mask of the pitch classes in the scale, starting at C.
+    pub pitch_classes: u16,
+    pub rounding: Rounding
+}
+
+/// The direction in which notes outside of the scale are moved.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Rounding {
+    /// Ties are moved down.
+    Nearest,
+    Up,
+    Down
+}
+
+/// Returns the note that the first matching rule moves the key to, snapped
+/// to the scale if there is one. With a scale, every key is mapped, and the
+/// keys that no rule matches are snapped to it. The range shifts and pitch
+/// classes are tried in order before the transpositions, which match every
+/// key. A rule that would move the note outside of the MIDI range leaves it
+/// unmapped.
+pub fn apply_rules(rules: &[Rule], scale: Option<&Scale>, key: u8) -> Option<u8> {
+    let is_transpose = |x: &&Rule| matches!(x, Rule::Transpose(_));
+
+    let note = rules.iter()
+        .filter(|x| !is_transpose(x))
+        .chain(rules.iter().filter(is_transpose))
+        .find_map(|rule| match rule {
+            Rule::Transpose(semitones) => Some(key as i16 + *semitones as i16),
+            Rule::Shift { from, to } if from.contains(&key) =>
+                Some(key as i16 - *from.start() as i16 + *to as i16),
+            Rule::PitchClass { from, to } if key % 12 == *from =>
+                Some(key as i16 - *from as i16 + *to as i16),
+            _ => None
+        })
+        .and_then(|note| (0..=127).contains(&note).then_some(note as u8));
+
+    match scale {
+        Some(scale) => Some(scale.snap(note.unwrap_or(key))),
+        None => note
+    }
+}
+
+#[inline]
+fn add_histogram(sum: &mut Histogram, histogram: Histogram) {
+    for (sum, count) in sum.iter_mut().zip(histogram) {
+        *sum += count;
+    }
+}
+
+#[inline]
+pub fn note_key(channel: u8, note: u8) -> u16 {
+    ((channel as u16) << 7) | note as u16
+}
+
+fn unique_notes(midi: &Smf, zones: &[Vec<MpeZone>]) -> Vec<Vec<TrackNote>> {
     let mut result = vec![];
 
-    for track in midi.tracks {
-        let mut set = IntSet::default();
+    for (track, zones) in midi.tracks.iter().zip(zones) {
+        let mut notes: IntMap<u8, u16> = IntMap::default();
 
         for event in track {
-            if let TrackEventKind::Midi { message, .. } = event.kind {
+            if let TrackEventKind::Midi { channel, message } = event.kind {
                 let note = match message {
                     MidiMessage::NoteOn { key, .. } => {
                         wmidi::Note::from_u8_lossy(key.as_int())
                     },
                     MidiMessage::NoteOff { key, .. } => {
                         wmidi::Note::from_u8_lossy(key.as_int())
+                    },
+                    MidiMessage::Aftertouch { key, .. } => {
+                        wmidi::Note::from_u8_lossy(key.as_int())
                     }
                     _ => continue
                 };
 
-                set.insert(note as u8);
+                *notes.entry(note as u8).or_default() |=
+                    1 << mpe::instrument(zones, channel.as_int());
             }
         }
 
-        result.push(set.into_iter()
-            .map(|x| unsafe { wmidi::Note::from_u8_unchecked(x) })
+        result.push(notes.into_iter()
+            .map(|(note, channels)| TrackNote {
+                note: unsafe { wmidi::Note::from_u8_unchecked(note) },
+                channels
+            })
             .collect()
         )
     }
     
     result
 }
+
+fn unique_controllers(midi: &Smf) -> Vec<Vec<u8>> {
+    let mut result = vec![];
+
+    for track in &midi.tracks {
+        let mut used = [false; 128];
+
+        for event in track {
+            if let TrackEventKind::Midi {
+                message: MidiMessage::Controller { controller, .. },
+                ..
+            } = event.kind {
+                used[controller.as_int() as usize] = true;
+            }
+        }
+
+        result.push((0..128).filter(|x| used[*x as usize]).collect());
+    }
+
+    result
+}
+
+fn unique_programs(midi: &Smf) -> Vec<Vec<Patch>> {
+    let mut result = vec![];
+
+    for track in &midi.tracks {
+        let mut banks = Banks::default();
+        let mut patches = vec![];
+
+        for event in track {
+            let TrackEventKind::Midi { channel, message } = event.kind else {
+                continue;
+            };
+
+            match message {
+                MidiMessage::Controller { controller, value } =>
+                    banks.update(channel.as_int(), controller.as_int(), value.as_int()),
+                MidiMessage::ProgramChange { program } =>
+                    patches.push(banks.patch(channel.as_int(), program.as_int())),
+                _ => { }
+            }
+        }
+
+        patches.sort();
+        patches.dedup();
+
+        result.push(patches);
+    }
+
+    result
+}
@@ -0,0 +1,387 @@
+use nohash_hasher::IntMap;
+
+use super::{
+    Output,
+    note_key,
+    mpe::{self, MpeZone},
+    events::{Events, NoteSpan, pair_notes, note_on, note_off, remove_marked},
+    tempo::{TempoMap, Span}
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Rudiment {
+    /// One grace note before the main hit.
+    Flam,
+    /// Two grace notes before the main hit.
+    Drag
+}
+
+/// Replaces the hits of an input note that are played as a rudiment with
+/// a single hit on an articulation note.
+#[derive(Clone, Copy, Debug)]
+pub struct Articulation {
+    /// The input note, see [`note_key`]. The notes on the member channels
+    /// of an MPE zone count as notes on its manager channel.
+    pub key: u16,
+    pub rudiment: Rudiment,
+    /// Every hit has to start within this span of the first one.
+    pub window: Span,
+    /// Only matches when the grace notes are softer than the main hit.
+    pub softer: bool,
+    pub output: Output
+}
+
+/// Expands every hit of a target into several strokes.
+#[derive(Clone, Copy, Debug)]
+pub struct Generator {
+    pub ornament: Ornament,
+    /// The time between two strokes.
+    pub offset: Span,
+    /// The velocity of the added strokes relative to the velocity of the hit.
+    pub velocity: f32
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ornament {
+    /// One grace note before the hit.
+    Flam,
+    /// Two grace notes before the hit.
+    Drag,
+    /// Repeats the hit until the input note ends.
+    Roll
+}
+
+impl Rudiment {
+    #[inline]
+    pub fn hits(self) -> usize {
+        match self {
+            Self::Flam => 2,
+            Self::Drag => 3
+        }
+    }
+}
+
+/// Moves the hits of every detected rudiment out of the events and adds
+/// their replacements to `detected`. The replacement starts with the first
+/// grace note, ends with the main hit and takes its velocity. Rudiments
+/// with more hits are detected first. Returns the number of rudiments
+/// that were replaced.
+pub fn detect<'a>(
+    events: &mut Events<'a>,
+    articulations: &[Articulation],
+    tempo: &TempoMap,
+    zones: &[MpeZone],
+    detected: &mut Events<'a>
+) -> usize {
+    if articulations.is_empty() {
+        return 0;
+    }
+
+    let mut by_key: IntMap<u16, Vec<&Articulation>> = IntMap::default();
+
+    for articulation in articulations {
+        by_key.entry(articulation.key).or_default().push(articulation);
+    }
+
+    for articulations in by_key.values_mut() {
+        articulations.sort_by_key(|x| std::cmp::Reverse(x.rudiment.hits()));
+    }
+
+    let notes = pair_notes(events);
+    let mut hits: IntMap<u16, Vec<&NoteSpan>> = IntMap::default();
+
+    for note in &notes {
+        let key = note_key(mpe::instrument(zones, note.channel), note.key);
+
+        if by_key.contains_key(&key) {
+            hits.entry(key).or_default().push(note);
+        }
+    }
+
+    let mut removed = vec![false; events.len()];
+    let mut count = 0;
+
+    for (key, hits) in &hits {
+        let mut i = 0;
+
+        while i < hits.len() {
+            let matched = by_key[key].iter().find(|x| matches(&hits[i..], x, tempo));
+
+            let Some(articulation) = matched else {
+                i += 1;
+                continue;
+            };
+
+            let len = articulation.rudiment.hits();
+            let group = &hits[i..i + len];
+            let main = group[len - 1];
+            i += len;
+
+            for note in group {
+                removed[note.on] = true;
+
+                if let Some(off) = note.off {
+                    removed[off] = true;
+                }
+            }
+
+            let channel = articulation.output.channel.unwrap_or(main.channel);
+            let key = articulation.output.note as u8;
+
+            detected.push((group[0].start, note_on(channel, key, main.vel)));
+
+            if let Some(end) = main.end {
+                detected.push((end, note_off(channel, key)));
+            }
+
+            count += 1;
+        }
+    }
+
+    remove_marked(events, &removed);
+
+    count
+}
+
+impl Generator {
+    /// Adds the grace notes that come before a hit at the given tick.
+    /// Each one lasts until the next stroke starts. The strokes are moved
+    /// closer together when there are not enough ticks before the hit and
+    /// left out when there are none.
+    pub fn grace_notes<'a>(
+        &self,
+        hit: u64,
+        vel: u8,
+        channel: u8,
+        key: u8,
+        tempo: &TempoMap,
+        generated: &mut Events<'a>
+    ) {
+        let count = match self.ornament {
+            Ornament::Flam => 1,
+            Ornament::Drag => 2,
+            Ornament::Roll => return
+        };
+
+        let offset = tempo.ticks_before(hit, self.offset).min(hit / count);
+
+        if offset == 0 {
+            return;
+        }
+
+        for i in (1..=count).rev() {
+            let start = hit - i * offset;
+
+            generated.push((start, note_on(channel, key, self.stroke_velocity(vel))));
+            generated.push((start + offset, note_off(channel, key)));
+        }
+    }
+
+    /// Adds the strokes that repeat a hit from its start until it ends.
+    /// Every stroke ends the one before it.
+    pub fn roll<'a>(
+        &self,
+        hit: u64,
+        end: u64,
+        vel: u8,
+        channel: u8,
+        key: u8,
+        tempo: &TempoMap,
+        generated: &mut Events<'a>
+    ) {
+        if self.ornament != Ornament::Roll {
+            return;
+        }
+
+        let mut tick = hit;
+
+        loop {
+            let offset = tempo.ticks(tick, self.offset);
+            tick += offset;
+
+            if offset == 0 || tick >= end {
+                break;
+            }
+
+            generated.push((tick, note_off(channel, key)));
+            generated.push((tick, note_on(channel, key, self.stroke_velocity(vel))));
+        }
+    }
+
+    #[inline]
+    fn stroke_velocity(&self, vel: u8) -> u8 {
+        (vel as f32 * self.velocity).round().clamp(1.0, 127.0) as u8
+    }
+}
+
+/// Whether the hits at the start of the slice form the rudiment.
+fn matches(hits: &[&NoteSpan], articulation: &Articulation, tempo: &TempoMap) -> bool {
+    let len = articulation.rudiment.hits();
+
+    if hits.len() < len {
+        return false;
+    }
+
+    let (start, main) = (hits[0].start, hits[len - 1]);
+
+    if main.start - start > tempo.ticks(start, articulation.window) {
+        return false;
+    }
+
+    !articulation.softer || hits[..len - 1].iter().all(|x| x.vel < main.vel)
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Smf, Header, Format, Timing, num::u15};
+
+    use super::*;
+
+    fn detected<'a>(events: &mut Events<'a>, articulations: &[Articulation]) -> (usize, Events<'a>) {
+        let midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        let mut detected = Vec::new();
+        let count = detect(events, articulations, &TempoMap::new(&midi), &[], &mut detected);
+
+        (count, detected)
+    }
+
+    fn articulation(rudiment: Rudiment, note: u8) -> Articulation {
+        Articulation {
+            key: note_key(9, 38),
+            rudiment,
+            window: Span::Ticks(20),
+            softer: true,
+            output: Output {
+                note: wmidi::Note::from_u8_lossy(note),
+                channel: None
+            }
+        }
+    }
+
+    #[test]
+    fn replaces_rudiments_with_their_articulation() {
+        let mut events = vec![
+            (0, note_on(9, 38, 40)),
+            (5, note_off(9, 38)),
+            (10, note_on(9, 38, 100)),
+            (50, note_off(9, 38)),
+            (100, note_on(9, 38, 90)),
+            (110, note_off(9, 38))
+        ];
+
+        assert_eq!(detected(&mut events, &[articulation(Rudiment::Flam, 39)]), (1, vec![
+            (0, note_on(9, 39, 100)),
+            (50, note_off(9, 39))
+        ]));
+        assert_eq!(events, [(100, note_on(9, 38, 90)), (110, note_off(9, 38))]);
+    }
+
+    #[test]
+    fn detects_longer_rudiments_first() {
+        let mut events = vec![
+            (0, note_on(9, 38, 30)),
+            (4, note_off(9, 38)),
+            (5, note_on(9, 38, 40)),
+            (9, note_off(9, 38)),
+            (10, note_on(9, 38, 100)),
+            (50, note_off(9, 38))
+        ];
+        let articulations = [articulation(Rudiment::Flam, 39), articulation(Rudiment::Drag, 40)];
+
+        assert_eq!(detected(&mut events, &articulations), (1, vec![
+            (0, note_on(9, 40, 100)),
+            (50, note_off(9, 40))
+        ]));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn keeps_hits_that_do_not_match() {
+        let events = vec![
+            (0, note_on(9, 38, 100)),
+            (5, note_off(9, 38)),
+            (10, note_on(9, 38, 100)),
+            (15, note_off(9, 38)),
+            (40, note_on(9, 38, 50)),
+            (45, note_off(9, 38)),
+            (70, note_on(9, 38, 100)),
+            (75, note_off(9, 38)),
+            (75, note_on(1, 38, 40)),
+            (80, note_on(9, 38, 100))
+        ];
+        let mut kept = events.clone();
+
+        // The grace note is as loud as the hit, too early, or on another channel.
+        assert_eq!(detected(&mut kept, &[articulation(Rudiment::Flam, 39)]), (0, vec![]));
+        assert_eq!(kept, events);
+
+        let loud = Articulation { softer: false, ..articulation(Rudiment::Flam, 39) };
+        assert_eq!(detected(&mut kept, &[loud]).0, 2);
+    }
+
+    fn generated(ornament: Ornament, strokes: impl FnOnce(&Generator, &TempoMap, &mut Events)) -> Events<'static> {
+        let midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        let generator = Generator { ornament, offset: Span::Ticks(30), velocity: 0.5 };
+        let mut generated = Vec::new();
+        strokes(&generator, &TempoMap::new(&midi), &mut generated);
+
+        generated
+    }
+
+    #[test]
+    fn adds_grace_notes_before_the_hit() {
+        let drag = |hit| generated(Ornament::Drag, |generator, tempo, generated| {
+            generator.grace_notes(hit, 100, 9, 38, tempo, generated)
+        });
+
+        assert_eq!(drag(100), [
+            (40, note_on(9, 38, 50)),
+            (70, note_off(9, 38)),
+            (70, note_on(9, 38, 50)),
+            (100, note_off(9, 38))
+        ]);
+        // The strokes are moved closer together near the start.
+        assert_eq!(drag(20), [
+            (0, note_on(9, 38, 50)),
+            (10, note_off(9, 38)),
+            (10, note_on(9, 38, 50)),
+            (20, note_off(9, 38))
+        ]);
+        assert!(drag(1).is_empty());
+        assert!(generated(Ornament::Roll, |generator, tempo, generated| {
+            generator.grace_notes(100, 100, 9, 38, tempo, generated)
+        }).is_empty());
+    }
+
+    #[test]
+    fn repeats_rolled_hits_until_they_end() {
+        let roll = |ornament| generated(ornament, |generator, tempo, generated| {
+            generator.roll(0, 90, 100, 9, 38, tempo, generated)
+        });
+
+        assert_eq!(roll(Ornament::Roll), [
+            (30, note_off(9, 38)),
+            (30, note_on(9, 38, 50)),
+            (60, note_off(9, 38)),
+            (60, note_on(9, 38, 50))
+        ]);
+        assert!(roll(Ornament::Flam).is_empty());
+    }
+
+    #[test]
+    fn detects_rudiments_across_the_members_of_an_mpe_zone() {
+        let midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        let zones = [MpeZone { manager: 9, members: 10..=14 }];
+        let mut events = vec![
+            (0, note_on(10, 38, 40)),
+            (5, note_off(10, 38)),
+            (10, note_on(11, 38, 100)),
+            (50, note_off(11, 38))
+        ];
+        let tempo = TempoMap::new(&midi);
+        let mut detected = Vec::new();
+
+        assert_eq!(detect(&mut events, &[articulation(Rudiment::Flam, 39)], &tempo, &zones, &mut detected), 1);
+        assert_eq!(detected, [(0, note_on(11, 39, 100)), (50, note_off(11, 39))]);
+    }
+}
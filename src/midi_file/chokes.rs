@@ -0,0 +1,185 @@
+use midly::{TrackEventKind, MidiMessage, num::{u4, u7}};
+
+use super::{
+    Output,
+    events::{Events, pair_notes, note_off, remove_marked, merge}
+};
+
+/// Outputs that cut each other off. A hit on any member ends the other
+/// members that are still sounding, like an open hi-hat is cut off by
+/// the closed one.
+#[derive(Clone, Debug)]
+pub struct ChokeGroup {
+    pub members: Vec<(Output, Choke)>
+}
+
+/// How a member of a choke group is cut off.
+#[derive(Clone, Copy, Debug)]
+pub enum Choke {
+    /// Ends the note early.
+    NoteOff,
+    /// Sends a controller message on the channel of the note and leaves
+    /// the note as it is.
+    Controller {
+        controller: u8,
+        value: u8
+    }
+}
+
+/// Returns the number of notes that were choked.
+pub fn insert(events: &mut Events, groups: &[ChokeGroup]) -> usize {
+    if groups.is_empty() {
+        return 0;
+    }
+
+    let notes = pair_notes(events);
+    let mut ends: Vec<Option<u64>> = notes.iter().map(|x| x.end).collect();
+    let mut choked = vec![false; notes.len()];
+    let mut added = Vec::new();
+    let mut count = 0;
+    // The notes of the group members that may still be sounding.
+    let mut sounding: Vec<usize> = Vec::new();
+
+    for (i, note) in notes.iter().enumerate() {
+        let in_group = groups.iter()
+            .any(|group| group.members.iter().any(|x| x.0.plays(note)));
+
+        if !in_group {
+            continue;
+        }
+
+        sounding.retain(|&j| !choked[j] && ends[j].map_or(true, |end| end > note.start));
+
+        for group in groups {
+            let Some(member) = group.members.iter().position(|x| x.0.plays(note)) else {
+                continue;
+            };
+
+            for &j in &sounding {
+                let other = &notes[j];
+
+                // Notes that start together don't choke each other.
+                if choked[j] || other.start == note.start {
+                    continue;
+                }
+
+                let choke = group.members.iter()
+                    .enumerate()
+                    .find(|(k, x)| *k != member && x.0.plays(other))
+                    .map(|(_, x)| x.1);
+
+                match choke {
+                    Some(Choke::NoteOff) => ends[j] = Some(note.start),
+                    Some(Choke::Controller { controller, value }) => {
+                        added.push((note.start, TrackEventKind::Midi {
+                            channel: u4::from_int_lossy(other.channel),
+                            message: MidiMessage::Controller {
+                                controller: u7::from_int_lossy(controller),
+                                value: u7::from_int_lossy(value)
+                            }
+                        }));
+                    },
+                    None => continue
+                }
+
+                choked[j] = true;
+                count += 1;
+            }
+        }
+
+        sounding.push(i);
+    }
+
+    let mut removed = vec![false; events.len()];
+
+    for (i, note) in notes.iter().enumerate() {
+        if ends[i] == note.end {
+            continue;
+        }
+
+        if let Some(off) = note.off {
+            removed[off] = true;
+        }
+
+        if let Some(end) = ends[i] {
+            added.push((end, note_off(note.channel, note.key)));
+        }
+    }
+
+    remove_marked(events, &removed);
+    merge(events, added);
+
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_file::events::note_on;
+
+    fn hi_hat(open: Choke) -> ChokeGroup {
+        let output = |note| Output { note: wmidi::Note::from_u8_lossy(note), channel: None };
+
+        ChokeGroup {
+            members: vec![(output(46), open), (output(42), Choke::NoteOff)]
+        }
+    }
+
+    #[test]
+    fn ends_choked_notes() {
+        let mut events = vec![
+            (0, note_on(9, 46, 100)),
+            (50, note_on(9, 42, 80)),
+            (60, note_off(9, 42)),
+            (100, note_off(9, 46))
+        ];
+
+        assert_eq!(insert(&mut events, &[hi_hat(Choke::NoteOff)]), 1);
+        assert_eq!(events, [
+            (0, note_on(9, 46, 100)),
+            (50, note_on(9, 42, 80)),
+            (50, note_off(9, 46)),
+            (60, note_off(9, 42))
+        ]);
+    }
+
+    #[test]
+    fn sends_the_choke_controller() {
+        let mut events = vec![
+            (0, note_on(9, 46, 100)),
+            (50, note_on(9, 42, 80)),
+            (60, note_off(9, 42)),
+            (100, note_off(9, 46))
+        ];
+        let choke = Choke::Controller { controller: 119, value: 0 };
+
+        assert_eq!(insert(&mut events, &[hi_hat(choke)]), 1);
+        assert_eq!(events[2], (50, TrackEventKind::Midi {
+            channel: u4::new(9),
+            message: MidiMessage::Controller { controller: u7::new(119), value: u7::new(0) }
+        }));
+        assert_eq!(events.len(), 5);
+    }
+
+    #[test]
+    fn leaves_notes_that_do_not_overlap() {
+        let events = vec![
+            // Notes that start together and retriggers of the same member.
+            (0, note_on(9, 46, 100)),
+            (0, note_on(9, 42, 100)),
+            (10, note_off(9, 42)),
+            (20, note_on(9, 46, 90)),
+            (30, note_off(9, 46)),
+            (40, note_off(9, 46)),
+            // A note that has already ended.
+            (50, note_on(9, 46, 100)),
+            (60, note_off(9, 46)),
+            (70, note_on(9, 42, 100)),
+            (80, note_off(9, 42))
+        ];
+        let mut kept = events.clone();
+
+        assert_eq!(insert(&mut kept, &[hi_hat(Choke::NoteOff)]), 0);
+        assert_eq!(kept, events);
+    }
+}
@@ -0,0 +1,286 @@
+use midly::{TrackEventKind, MetaMessage};
+use nohash_hasher::IntMap;
+
+use super::{
+    Output,
+    mpe::{self, MpeZone},
+    events::{Events, NoteSpan, pair_notes, note_on, note_off, remove_marked},
+    tempo::{TempoMap, Span}
+};
+
+/// Replaces groups of notes that start together with a single output.
+#[derive(Clone, Debug)]
+pub struct Chords {
+    /// Notes on the same channel that start within this span of the first
+    /// one form a group. The member channels of an MPE zone count as its
+    /// manager channel.
+    pub tolerance: Span,
+    /// The first pattern that matches a group is used.
+    pub patterns: Vec<Chord>
+}
+
+#[derive(Clone, Debug)]
+pub struct Chord {
+    pub notes: ChordNotes,
+    pub output: ChordOutput
+}
+
+/// The notes a group has to consist of, in any order. Notes that are
+/// played more than once in a group only count once.
+#[derive(Clone, Debug)]
+pub enum ChordNotes {
+    /// Matches these exact keys.
+    Keys(Vec<u8>),
+    /// Matches these intervals in semitones above the lowest note of the
+    /// group. The lowest note itself is always included.
+    Intervals(Vec<u8>)
+}
+
+#[derive(Clone, Debug)]
+pub enum ChordOutput {
+    Note(Output),
+    /// Keeps only the lowest note of the group and adds a marker with the
+    /// given text where the group starts.
+    Root {
+        marker: String
+    }
+}
+
+/// Moves the notes of every group that matches a pattern out of the events
+/// and adds their replacements to `collapsed`. The replacement note starts
+/// with the group, lasts until its last note ends and takes the highest
+/// velocity. Returns the number of groups that were replaced.
+pub fn collapse<'a>(
+    events: &mut Events<'a>,
+    chords: &'a Chords,
+    tempo: &TempoMap,
+    zones: &[MpeZone],
+    collapsed: &mut Events<'a>
+) -> usize {
+    let patterns: Vec<(Vec<u8>, bool)> = chords.patterns.iter()
+        .map(|chord| {
+            let (notes, relative) = match &chord.notes {
+                ChordNotes::Keys(keys) => (keys, false),
+                ChordNotes::Intervals(intervals) => (intervals, true)
+            };
+
+            let mut notes = notes.clone();
+
+            if relative {
+                notes.push(0);
+            }
+
+            notes.sort_unstable();
+            notes.dedup();
+
+            (notes, relative)
+        })
+        .collect();
+
+    let notes = pair_notes(events);
+    let mut by_channel: IntMap<u8, Vec<&NoteSpan>> = IntMap::default();
+
+    for note in &notes {
+        by_channel.entry(mpe::instrument(zones, note.channel)).or_default().push(note);
+    }
+
+    let mut removed = vec![false; events.len()];
+    let mut groups = 0;
+
+    for notes in by_channel.values() {
+        let mut i = 0;
+
+        while i < notes.len() {
+            let start = notes[i].start;
+            let window = tempo.ticks(start, chords.tolerance);
+            let len = notes[i..].iter()
+                .take_while(|x| x.start - start <= window)
+                .count();
+
+            let group = &notes[i..i + len];
+
+            // A window that matches no pattern may still start a chord at its
+            // next note, e.g. after a stray note just before the chord.
+            if group.len() < 2 {
+                i += 1;
+                continue;
+            }
+
+            let mut keys: Vec<u8> = group.iter().map(|x| x.key).collect();
+            keys.sort_unstable();
+            keys.dedup();
+
+            let lowest = keys[0];
+            let intervals: Vec<u8> = keys.iter().map(|x| x - lowest).collect();
+
+            let matched = patterns.iter().position(|(notes, relative)| match relative {
+                true => *notes == intervals,
+                false => *notes == keys
+            });
+
+            let Some(matched) = matched else {
+                i += 1;
+                continue;
+            };
+
+            i += len;
+
+            for note in group {
+                removed[note.on] = true;
+
+                if let Some(off) = note.off {
+                    removed[off] = true;
+                }
+            }
+
+            let channel = group[0].channel;
+            let vel = group.iter().map(|x| x.vel).max().unwrap_or(127);
+            let end = group.iter().map(|x| x.end).max().flatten();
+
+            let (channel, key) = match &chords.patterns[matched].output {
+                ChordOutput::Note(output) =>
+                    (output.channel.unwrap_or(channel), output.note as u8),
+                ChordOutput::Root { marker } => {
+                    collapsed.push((
+                        start,
+                        TrackEventKind::Meta(MetaMessage::Marker(marker.as_bytes()))
+                    ));
+
+                    (channel, lowest)
+                }
+            };
+
+            collapsed.push((start, note_on(channel, key, vel)));
+
+            if let Some(end) = end {
+                collapsed.push((end, note_off(channel, key)));
+            }
+
+            groups += 1;
+        }
+    }
+
+    remove_marked(events, &removed);
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Smf, Header, Format, Timing, num::u15};
+
+    use super::*;
+
+    fn chords(patterns: Vec<Chord>) -> Chords {
+        Chords { tolerance: Span::Ticks(5), patterns }
+    }
+
+    fn collapsed<'a>(events: &mut Events<'a>, chords: &'a Chords) -> (usize, Events<'a>) {
+        let midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        let mut collapsed = Vec::new();
+        let groups = collapse(events, chords, &TempoMap::new(&midi), &[], &mut collapsed);
+
+        (groups, collapsed)
+    }
+
+    fn triad() -> Chord {
+        Chord {
+            notes: ChordNotes::Intervals(vec![4, 7]),
+            output: ChordOutput::Note(Output {
+                note: wmidi::Note::from_u8_lossy(36),
+                channel: Some(9)
+            })
+        }
+    }
+
+    #[test]
+    fn replaces_groups_with_a_single_note() {
+        let chords = chords(vec![triad()]);
+        let mut events = vec![
+            (0, note_on(0, 60, 50)),
+            (2, note_on(0, 64, 80)),
+            (4, note_on(0, 67, 60)),
+            (100, note_off(0, 60)),
+            (105, note_off(0, 67)),
+            (110, note_off(0, 64))
+        ];
+
+        assert_eq!(collapsed(&mut events, &chords), (1, vec![
+            (0, note_on(9, 36, 80)),
+            (110, note_off(9, 36))
+        ]));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn starts_groups_after_stray_notes() {
+        let chords = chords(vec![triad()]);
+        let mut events = vec![
+            (0, note_on(0, 30, 50)),
+            (3, note_on(0, 62, 50)),
+            (5, note_on(0, 66, 50)),
+            (5, note_on(0, 69, 50)),
+            (5, note_on(1, 40, 50)),
+            (50, note_off(0, 30)),
+            (50, note_off(0, 62)),
+            (50, note_off(0, 66)),
+            (50, note_off(0, 69)),
+            (50, note_off(1, 40))
+        ];
+
+        assert_eq!(collapsed(&mut events, &chords), (1, vec![
+            (3, note_on(9, 36, 50)),
+            (50, note_off(9, 36))
+        ]));
+        assert_eq!(events, [
+            (0, note_on(0, 30, 50)),
+            (5, note_on(1, 40, 50)),
+            (50, note_off(0, 30)),
+            (50, note_off(1, 40))
+        ]);
+    }
+
+    #[test]
+    fn keeps_the_root_of_exact_keys() {
+        let mut events = vec![
+            (0, note_on(2, 38, 70)),
+            (1, note_on(2, 36, 90)),
+            (1, note_on(2, 38, 40)),
+            (20, note_off(2, 36)),
+            (20, note_off(2, 38)),
+            (20, note_off(2, 38))
+        ];
+        let root = Chord {
+            notes: ChordNotes::Keys(vec![38, 36]),
+            output: ChordOutput::Root { marker: "flam".into() }
+        };
+        let chords = chords(vec![triad(), root]);
+
+        assert_eq!(collapsed(&mut events, &chords), (1, vec![
+            (0, TrackEventKind::Meta(MetaMessage::Marker(b"flam"))),
+            (0, note_on(2, 36, 90)),
+            (20, note_off(2, 36))
+        ]));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn groups_the_notes_of_an_mpe_zone() {
+        let midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        let zones = [MpeZone { manager: 0, members: 1..=15 }];
+        let chords = chords(vec![triad()]);
+        let mut events = vec![
+            (0, note_on(1, 60, 50)),
+            (0, note_on(2, 64, 50)),
+            (0, note_on(3, 67, 50)),
+            (50, note_off(1, 60)),
+            (50, note_off(2, 64)),
+            (50, note_off(3, 67))
+        ];
+        let tempo = TempoMap::new(&midi);
+        let mut collapsed = Vec::new();
+
+        assert_eq!(collapse(&mut events, &chords, &tempo, &zones, &mut collapsed), 1);
+        assert!(events.is_empty());
+    }
+}
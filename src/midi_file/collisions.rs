@@ -0,0 +1,213 @@
+use midly::{TrackEventKind, MidiMessage, num::u7};
+use nohash_hasher::IntMap;
+
+use super::{
+    note_key,
+    events::{Events, pair_notes, note_off, remove_marked, merge},
+    tempo::{TempoMap, Span}
+};
+
+/// How to resolve several hits that land on the same output note at once.
+/// Hits that only overlap are always fixed by ending the earlier note
+/// where the later one starts.
+#[derive(Clone, Copy, Debug)]
+pub enum CollisionPolicy {
+    /// Keeps the loudest of the hits on the same tick.
+    KeepLoudest,
+    /// Merges the hits on the same tick into one with the highest velocity
+    /// that lasts as long as the longest of them.
+    Merge,
+    /// Drops any hit that comes within the span after the previous one.
+    Retrigger(Span)
+}
+
+/// Returns the number of collisions that were fixed.
+pub fn resolve<'a>(
+    events: &mut Events<'a>,
+    policy: CollisionPolicy,
+    tempo: &TempoMap
+) -> usize {
+    let original = pair_notes(events);
+    let mut notes = original.clone();
+    let mut dropped = vec![false; notes.len()];
+    let mut collisions = 0;
+
+    let mut by_key: IntMap<u16, Vec<usize>> = IntMap::default();
+
+    for (i, note) in notes.iter().enumerate() {
+        by_key.entry(note_key(note.channel, note.key)).or_default().push(i);
+    }
+
+    for indices in by_key.values() {
+        let mut current: Option<usize> = None;
+
+        for &next in indices {
+            let Some(prev) = current else {
+                current = Some(next);
+                continue;
+            };
+
+            let start = notes[prev].start;
+            let distance = notes[next].start - start;
+
+            let simultaneous = match policy {
+                CollisionPolicy::Retrigger(span) => distance <= tempo.ticks(start, span),
+                _ => distance == 0
+            };
+
+            if simultaneous {
+                collisions += 1;
+
+                match policy {
+                    CollisionPolicy::KeepLoudest => {
+                        if notes[next].vel > notes[prev].vel {
+                            dropped[prev] = true;
+                            current = Some(next);
+                        } else {
+                            dropped[next] = true;
+                        }
+                    },
+                    CollisionPolicy::Merge => {
+                        notes[prev].vel = notes[prev].vel.max(notes[next].vel);
+
+                        if let (Some(end), Some(other)) = (notes[prev].end, notes[next].end) {
+                            notes[prev].end = Some(end.max(other));
+                        }
+
+                        dropped[next] = true;
+                    },
+                    CollisionPolicy::Retrigger(_) => dropped[next] = true
+                }
+
+                continue;
+            }
+
+            if notes[prev].end.map_or(true, |end| end > notes[next].start) {
+                notes[prev].end = Some(notes[next].start);
+                collisions += 1;
+            }
+
+            current = Some(next);
+        }
+    }
+
+    let mut removed = vec![false; events.len()];
+    let mut added = Vec::new();
+
+    for (i, note) in notes.iter().enumerate() {
+        if dropped[i] {
+            removed[note.on] = true;
+
+            if let Some(off) = note.off {
+                removed[off] = true;
+            }
+
+            continue;
+        }
+
+        if note.vel != original[i].vel {
+            if let TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } =
+                &mut events[note.on].1
+            {
+                *vel = u7::new(note.vel);
+            }
+        }
+
+        if note.end != original[i].end {
+            if let Some(off) = note.off {
+                removed[off] = true;
+            }
+
+            if let Some(end) = note.end {
+                added.push((end, note_off(note.channel, note.key)));
+            }
+        }
+    }
+
+    remove_marked(events, &removed);
+    merge(events, added);
+
+    collisions
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Smf, Header, Format, Timing, num::u15};
+
+    use super::*;
+    use crate::midi_file::events::note_on;
+
+    fn resolved(mut events: Events, policy: CollisionPolicy) -> (usize, Vec<(u64, Option<u64>, u8)>) {
+        let midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        let collisions = resolve(&mut events, policy, &TempoMap::new(&midi));
+
+        // The NoteOffs that end the trimmed notes are merged in order.
+        assert!(events.windows(2).all(|x| x[0].0 <= x[1].0));
+
+        let mut notes: Vec<_> =
+            pair_notes(&events).iter().map(|x| (x.start, x.end, x.vel)).collect();
+        notes.sort();
+
+        (collisions, notes)
+    }
+
+    /// A loud short hit and a quiet long hit on the same tick.
+    fn simultaneous<'a>() -> Events<'a> {
+        vec![
+            (0, note_on(0, 60, 90)),
+            (0, note_on(0, 60, 50)),
+            (100, note_off(0, 60)),
+            (200, note_off(0, 60))
+        ]
+    }
+
+    #[test]
+    fn keeps_the_loudest_hit() {
+        let (collisions, notes) = resolved(simultaneous(), CollisionPolicy::KeepLoudest);
+
+        assert_eq!(collisions, 1);
+        assert_eq!(notes, [(0, Some(100), 90)]);
+    }
+
+    #[test]
+    fn merges_hits() {
+        let (collisions, notes) = resolved(simultaneous(), CollisionPolicy::Merge);
+
+        assert_eq!(collisions, 1);
+        assert_eq!(notes, [(0, Some(200), 90)]);
+    }
+
+    #[test]
+    fn drops_retriggers_within_the_span() {
+        // 50 ms are 48 ticks at 120 BPM.
+        let events = vec![
+            (0, note_on(0, 60, 90)),
+            (40, note_on(0, 60, 80)),
+            (45, note_off(0, 60)),
+            (60, note_off(0, 60)),
+            (100, note_on(0, 60, 70)),
+            (150, note_off(0, 60))
+        ];
+        let (collisions, notes) =
+            resolved(events, CollisionPolicy::Retrigger(Span::Millis(50.0)));
+
+        assert_eq!(collisions, 1);
+        assert_eq!(notes, [(0, Some(45), 90), (100, Some(150), 70)]);
+    }
+
+    #[test]
+    fn ends_overlapping_notes() {
+        let events = vec![
+            (0, note_on(0, 60, 90)),
+            (200, note_on(0, 60, 80)),
+            (300, note_off(0, 60)),
+            (400, note_off(0, 60)),
+            (200, note_on(1, 60, 70)),
+            (250, note_off(1, 60))
+        ];
+        let (collisions, notes) = resolved(events, CollisionPolicy::KeepLoudest);
+
+        assert_eq!(collisions, 1);
+        assert_eq!(notes, [(0, Some(200), 90), (200, Some(250), 70), (200, Some(400), 80)]);
+    }
+}
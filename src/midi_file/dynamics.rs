@@ -0,0 +1,176 @@
+use std::ops::RangeInclusive;
+
+use midly::{TrackEventKind, MidiMessage, num::u7};
+use nohash_hasher::IntMap;
+
+use super::{
+    note_key,
+    events::Events
+};
+
+/// The number of hits in each velocity band of 8, starting at 1.
+pub type Histogram = [usize; 16];
+
+/// Evens out the velocities of every output note of a track, so that
+/// grooves from different sources play at the same level.
+#[derive(Clone, Debug)]
+pub enum Dynamics {
+    /// Stretches the velocities of each note to the range and moves their
+    /// median to the given value.
+    Normalize {
+        range: RangeInclusive<u8>,
+        median: u8
+    },
+    /// Divides the part of each velocity above the threshold by the ratio.
+    Compress {
+        threshold: u8,
+        ratio: f32
+    }
+}
+
+pub fn histogram(events: &Events) -> Histogram {
+    let mut histogram = [0; 16];
+
+    for (_, kind) in events {
+        if let TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } = kind {
+            if *vel > 0 {
+                histogram[(vel.as_int() as usize - 1) / 8] += 1;
+            }
+        }
+    }
+
+    histogram
+}
+
+/// Returns the number of hits whose velocity was changed.
+pub fn apply(events: &mut Events, dynamics: &Dynamics) -> usize {
+    // The indices of the hits of each note.
+    let mut hits: IntMap<u16, Vec<usize>> = IntMap::default();
+
+    for (i, (_, kind)) in events.iter().enumerate() {
+        if let TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } } = kind {
+            if *vel > 0 {
+                hits.entry(note_key(channel.as_int(), key.as_int())).or_default().push(i);
+            }
+        }
+    }
+
+    let mut changed = 0;
+
+    for hits in hits.values() {
+        let mut velocities: Vec<u8> = hits.iter().map(|x| velocity(&events[*x].1)).collect();
+        velocities.sort_unstable();
+
+        let (min, max) = (velocities[0] as f32, velocities[velocities.len() - 1] as f32);
+        let median = velocities[(velocities.len() - 1) / 2] as f32;
+
+        for &i in hits {
+            let vel = velocity(&events[i].1) as f32;
+
+            let new_vel = match dynamics {
+                Dynamics::Normalize { range, median: target } => {
+                    let (low, high, target) =
+                        (*range.start() as f32, *range.end() as f32, *target as f32);
+
+                    if vel < median {
+                        low + (vel - min) / (median - min) * (target - low)
+                    } else if vel > median {
+                        target + (vel - median) / (max - median) * (high - target)
+                    } else {
+                        target
+                    }
+                },
+                Dynamics::Compress { threshold, ratio } => {
+                    let threshold = *threshold as f32;
+
+                    match vel > threshold {
+                        true => threshold + (vel - threshold) / ratio.max(1.0),
+                        false => vel
+                    }
+                }
+            };
+
+            let new_vel = new_vel.round().clamp(1.0, 127.0) as u8;
+
+            if let TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } =
+                &mut events[i].1
+            {
+                if vel.as_int() != new_vel {
+                    *vel = u7::new(new_vel);
+                    changed += 1;
+                }
+            }
+        }
+    }
+
+    changed
+}
+
+#[inline]
+fn velocity(kind: &TrackEventKind) -> u8 {
+    match kind {
+        TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } => vel.as_int(),
+        _ => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_file::events::{note_on, note_off, is_note_off};
+
+    fn velocities(events: &Events) -> Vec<u8> {
+        events.iter()
+            .filter(|x| !is_note_off(&x.1))
+            .map(|x| velocity(&x.1))
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_each_note() {
+        let mut events = vec![
+            (0, note_on(9, 38, 40)),
+            (0, note_on(9, 42, 70)),
+            (5, note_off(9, 38)),
+            (10, note_on(9, 38, 100)),
+            (20, note_on(9, 38, 60))
+        ];
+        let dynamics = Dynamics::Normalize { range: 20..=120, median: 80 };
+
+        assert_eq!(apply(&mut events, &dynamics), 4);
+        assert_eq!(velocities(&events), [20, 80, 120, 80]);
+    }
+
+    #[test]
+    fn compresses_loud_hits() {
+        let mut events = vec![
+            (0, note_on(9, 38, 120)),
+            (10, note_on(9, 38, 90)),
+            (20, note_on(9, 38, 0))
+        ];
+
+        assert_eq!(apply(&mut events, &Dynamics::Compress { threshold: 100, ratio: 2.0 }), 1);
+        assert_eq!(velocities(&events), [110, 90]);
+
+        // Ratios below 1 leave the velocities as they are.
+        assert_eq!(apply(&mut events, &Dynamics::Compress { threshold: 50, ratio: 0.5 }), 0);
+    }
+
+    #[test]
+    fn counts_hits_by_velocity() {
+        let events = vec![
+            (0, note_on(9, 38, 1)),
+            (0, note_on(9, 38, 8)),
+            (0, note_on(9, 38, 9)),
+            (0, note_on(9, 38, 127)),
+            (0, note_on(9, 38, 0))
+        ];
+
+        let mut expected = [0; 16];
+        expected[0] = 2;
+        expected[1] = 1;
+        expected[15] = 1;
+
+        assert_eq!(histogram(&events), expected);
+    }
+}
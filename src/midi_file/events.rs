@@ -0,0 +1,299 @@
+use midly::{Track, TrackEvent, TrackEventKind, MidiMessage, MetaMessage, num::{u4, u7, u28}};
+use nohash_hasher::IntMap;
+
+use super::note_key;
+
+/// Track events with absolute tick positions instead of deltas.
+pub type Events<'a> = Vec<(u64, TrackEventKind<'a>)>;
+
+/// A NoteOn paired with the NoteOff that ends it. Both are referenced by
+/// their index in the [`Events`] they were paired from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NoteSpan {
+    pub channel: u8,
+    pub key: u8,
+    pub vel: u8,
+    pub start: u64,
+    pub end: Option<u64>,
+    pub on: usize,
+    pub off: Option<usize>
+}
+
+pub fn to_absolute(track: Track) -> Events {
+    let mut tick = 0;
+
+    track.into_iter().map(|event| {
+        tick += event.delta.as_int() as u64;
+
+        (tick, event.kind)
+    }).collect()
+}
+
+/// Sorts the events and converts them back to deltas. A NoteOff that ends a
+/// note from an earlier tick is placed before the other events on its tick so
+/// that it never cuts short a note that starts there, while a note that starts
+/// and ends on the same tick keeps its NoteOn first. The end of track is
+/// always kept last.
+pub fn to_track(mut events: Events) -> Track {
+    let end = events.iter()
+        .position(|(_, kind)| matches!(kind, TrackEventKind::Meta(MetaMessage::EndOfTrack)))
+        .map(|i| events.remove(i));
+
+    events.sort_by_key(|x| x.0);
+    order_note_offs(&mut events);
+
+    if let Some((tick, kind)) = end {
+        let last = events.last().map_or(0, |x| x.0);
+        events.push((tick.max(last), kind));
+    }
+
+    let mut track = Vec::with_capacity(events.len());
+    let mut last = 0;
+
+    for (tick, kind) in events {
+        track.push(TrackEvent {
+            delta: u28::from_int_lossy((tick - last) as u32),
+            kind
+        });
+
+        last = tick;
+    }
+
+    track
+}
+
+/// Moves the NoteOffs that end a note from an earlier tick to the start of
+/// their tick. The order of the other events is kept.
+fn order_note_offs(events: &mut Events) {
+    // The number of notes sounding on each key, keyed by [`note_key`].
+    let mut sounding: IntMap<u16, usize> = IntMap::default();
+    let mut start = 0;
+
+    while start < events.len() {
+        let tick = events[start].0;
+        let end = start + events[start..].iter().take_while(|x| x.0 == tick).count();
+        let group = &mut events[start..end];
+
+        let ends_earlier: Vec<bool> = group.iter()
+            .map(|(_, kind)| match note(kind) {
+                Some((key, false)) => match sounding.get_mut(&key) {
+                    Some(count) if *count > 0 => {
+                        *count -= 1;
+                        true
+                    },
+                    _ => false
+                },
+                _ => false
+            })
+            .collect();
+
+        for ((_, kind), ends_earlier) in group.iter().zip(&ends_earlier) {
+            match note(kind) {
+                Some((key, true)) => *sounding.entry(key).or_default() += 1,
+                Some((key, false)) if !ends_earlier => {
+                    if let Some(count) = sounding.get_mut(&key) {
+                        *count = count.saturating_sub(1);
+                    }
+                },
+                _ => { }
+            }
+        }
+
+        if ends_earlier.contains(&true) {
+            let (early, rest): (Vec<_>, Vec<_>) = group.iter()
+                .zip(&ends_earlier)
+                .partition(|x| *x.1);
+            let ordered: Vec<_> = early.into_iter().chain(rest).map(|x| *x.0).collect();
+
+            group.copy_from_slice(&ordered);
+        }
+
+        start = end;
+    }
+}
+
+/// Adds the events to the sorted ones, keeping them sorted. Events on the
+/// same tick are placed after the existing ones.
+pub fn merge<'a>(events: &mut Events<'a>, other: Events<'a>) {
+    if other.is_empty() {
+        return;
+    }
+
+    events.extend(other);
+    events.sort_by_key(|x| x.0);
+}
+
+/// Pairs every NoteOn with the first NoteOff on the same channel and key
+/// that follows it. The spans are returned in the order the notes start.
+pub fn pair_notes(events: &Events) -> Vec<NoteSpan> {
+    let mut notes: Vec<NoteSpan> = Vec::new();
+    let mut sounding: IntMap<u16, Vec<usize>> = IntMap::default();
+
+    for (i, (tick, kind)) in events.iter().enumerate() {
+        let TrackEventKind::Midi { channel, message } = kind else {
+            continue;
+        };
+
+        let channel = channel.as_int();
+
+        match *message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                sounding.entry(note_key(channel, key.as_int()))
+                    .or_default()
+                    .push(notes.len());
+
+                notes.push(NoteSpan {
+                    channel,
+                    key: key.as_int(),
+                    vel: vel.as_int(),
+                    start: *tick,
+                    end: None,
+                    on: i,
+                    off: None
+                });
+            },
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                let Some(open) = sounding.get_mut(&note_key(channel, key.as_int())) else {
+                    continue;
+                };
+
+                if !open.is_empty() {
+                    let note = &mut notes[open.remove(0)];
+                    note.end = Some(*tick);
+                    note.off = Some(i);
+                }
+            },
+            _ => { }
+        }
+    }
+
+    notes
+}
+
+/// Removes the events whose index is marked.
+pub fn remove_marked(events: &mut Events, removed: &[bool]) {
+    let mut i = 0;
+    events.retain(|_| {
+        i += 1;
+        !removed[i - 1]
+    });
+}
+
+#[inline]
+pub fn note_on<'a>(channel: u8, key: u8, vel: u8) -> TrackEventKind<'a> {
+    TrackEventKind::Midi {
+        channel: u4::from_int_lossy(channel),
+        message: MidiMessage::NoteOn {
+            key: u7::from_int_lossy(key),
+            vel: u7::from_int_lossy(vel)
+        }
+    }
+}
+
+#[inline]
+pub fn note_off<'a>(channel: u8, key: u8) -> TrackEventKind<'a> {
+    TrackEventKind::Midi {
+        channel: u4::from_int_lossy(channel),
+        message: MidiMessage::NoteOff {
+            key: u7::from_int_lossy(key),
+            vel: u7::new(0)
+        }
+    }
+}
+
+#[inline]
+pub fn is_note_off(kind: &TrackEventKind) -> bool {
+    match kind {
+        TrackEventKind::Midi { message, .. } => match message {
+            MidiMessage::NoteOff { .. } => true,
+            MidiMessage::NoteOn { vel, .. } => *vel == 0,
+            _ => false
+        },
+        _ => false
+    }
+}
+
+/// The [`note_key`] of a NoteOn or NoteOff and whether it starts a note.
+#[inline]
+fn note(kind: &TrackEventKind) -> Option<(u16, bool)> {
+    match *kind {
+        TrackEventKind::Midi { channel, message } => match message {
+            MidiMessage::NoteOn { key, vel } =>
+                Some((note_key(channel.as_int(), key.as_int()), vel > 0)),
+            MidiMessage::NoteOff { key, .. } =>
+                Some((note_key(channel.as_int(), key.as_int()), false)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_zero_length_notes_in_order() {
+        let events = vec![
+            (0, note_on(9, 42, 100)),
+            (0, note_off(9, 42)),
+            (10, note_on(9, 42, 90)),
+            (10, note_on(9, 42, 0))
+        ];
+
+        assert_eq!(to_absolute(to_track(events.clone())), events);
+    }
+
+    #[test]
+    fn ends_earlier_notes_before_new_ones() {
+        let events = vec![
+            (0, note_on(0, 60, 100)),
+            (10, note_on(0, 60, 90)),
+            (10, note_on(0, 62, 90)),
+            (10, note_off(0, 62)),
+            (10, note_off(0, 60)),
+            (20, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+            (20, note_off(0, 60))
+        ];
+
+        assert_eq!(to_absolute(to_track(events)), [
+            (0, note_on(0, 60, 100)),
+            (10, note_off(0, 60)),
+            (10, note_on(0, 60, 90)),
+            (10, note_on(0, 62, 90)),
+            (10, note_off(0, 62)),
+            (20, note_off(0, 60)),
+            (20, TrackEventKind::Meta(MetaMessage::EndOfTrack))
+        ]);
+    }
+
+    #[test]
+    fn pairs_note_offs_in_order() {
+        let events = vec![
+            (0, note_on(0, 60, 100)),
+            (5, note_on(0, 60, 80)),
+            (10, note_off(0, 60)),
+            (12, note_off(1, 60)),
+            (20, note_on(0, 60, 0)),
+            (30, note_on(0, 60, 70))
+        ];
+        let spans: Vec<_> = pair_notes(&events).iter()
+            .map(|x| (x.start, x.end, x.vel))
+            .collect();
+
+        assert_eq!(spans, [(0, Some(10), 100), (5, Some(20), 80), (30, None, 70)]);
+    }
+
+    #[test]
+    fn merges_after_events_on_the_same_tick() {
+        let mut events = vec![(0, note_on(0, 60, 100)), (10, note_off(0, 60))];
+        merge(&mut events, vec![(10, note_on(0, 61, 100)), (5, note_off(0, 59))]);
+
+        assert_eq!(events, [
+            (0, note_on(0, 60, 100)),
+            (5, note_off(0, 59)),
+            (10, note_off(0, 60)),
+            (10, note_on(0, 61, 100))
+        ]);
+    }
+}
@@ -0,0 +1,133 @@
+use nohash_hasher::IntMap;
+
+use super::{
+    Output,
+    note_key,
+    events::{Events, pair_notes, note_off, remove_marked, merge},
+    tempo::{TempoMap, Span}
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NoteLength {
+    /// Every note lasts exactly this long.
+    Fixed(Span),
+    /// Shorter notes are made this long.
+    Minimum(Span)
+}
+
+/// Moves the NoteOffs of the notes played on the outputs so that they
+/// have the given length and adds the missing ones. A note never lasts
+/// past the next hit of the same note. Returns the number of notes that
+/// were changed.
+pub fn apply(events: &mut Events, lengths: &[(Output, NoteLength)], tempo: &TempoMap) -> usize {
+    if lengths.is_empty() {
+        return 0;
+    }
+
+    let notes = pair_notes(events);
+    let mut next_start: Vec<Option<u64>> = vec![None; notes.len()];
+    let mut last: IntMap<u16, usize> = IntMap::default();
+
+    for (i, note) in notes.iter().enumerate() {
+        if let Some(prev) = last.insert(note_key(note.channel, note.key), i) {
+            next_start[prev] = Some(note.start);
+        }
+    }
+
+    let mut removed = vec![false; events.len()];
+    let mut added = Vec::new();
+    let mut changed = 0;
+
+    for (i, note) in notes.iter().enumerate() {
+        let Some((_, length)) = lengths.iter().find(|x| x.0.plays(note)) else {
+            continue;
+        };
+
+        let end = match *length {
+            NoteLength::Fixed(span) => note.start + tempo.ticks(note.start, span),
+            NoteLength::Minimum(span) => {
+                let min = note.start + tempo.ticks(note.start, span);
+                note.end.map_or(min, |end| end.max(min))
+            }
+        };
+
+        let end = next_start[i].map_or(end, |next| end.min(next));
+
+        if note.end == Some(end) {
+            continue;
+        }
+
+        if let Some(off) = note.off {
+            removed[off] = true;
+        }
+
+        added.push((end, note_off(note.channel, note.key)));
+        changed += 1;
+    }
+
+    remove_marked(events, &removed);
+    merge(events, added);
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Smf, Header, Format, Timing, num::u15};
+
+    use super::*;
+    use crate::midi_file::events::note_on;
+
+    fn lengthened(events: &mut Events, length: NoteLength) -> usize {
+        let midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        let output = Output { note: wmidi::Note::from_u8_lossy(38), channel: None };
+
+        apply(events, &[(output, length)], &TempoMap::new(&midi))
+    }
+
+    #[test]
+    fn sets_fixed_lengths() {
+        let mut events = vec![
+            (0, note_on(9, 38, 100)),
+            (5, note_off(9, 38)),
+            (100, note_on(9, 38, 100)),
+            (150, note_on(9, 38, 100)),
+            (300, note_off(9, 38)),
+            (300, note_on(9, 40, 100)),
+            (310, note_off(9, 40))
+        ];
+
+        // The second hit is cut short by the third one and the last one
+        // gets the NoteOff it was missing.
+        assert_eq!(lengthened(&mut events, NoteLength::Fixed(Span::Ticks(60))), 3);
+        assert_eq!(events, [
+            (0, note_on(9, 38, 100)),
+            (60, note_off(9, 38)),
+            (100, note_on(9, 38, 100)),
+            (150, note_on(9, 38, 100)),
+            (150, note_off(9, 38)),
+            (210, note_off(9, 38)),
+            (300, note_on(9, 40, 100)),
+            (310, note_off(9, 40))
+        ]);
+    }
+
+    #[test]
+    fn extends_short_notes() {
+        let mut events = vec![
+            (0, note_on(9, 38, 100)),
+            (0, note_off(9, 38)),
+            (100, note_on(9, 38, 100)),
+            (200, note_off(9, 38))
+        ];
+
+        // Zero-length notes are extended too.
+        assert_eq!(lengthened(&mut events, NoteLength::Minimum(Span::Ticks(60))), 1);
+        assert_eq!(events, [
+            (0, note_on(9, 38, 100)),
+            (60, note_off(9, 38)),
+            (100, note_on(9, 38, 100)),
+            (200, note_off(9, 38))
+        ]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::ops::RangeInclusive;
+
+use midly::{Smf, Track, TrackEventKind, MidiMessage};
+
+/// An MPE zone, set up by the MPE configuration message (RPN 6) on its
+/// manager channel. Each note of the zone is played on one of the member
+/// channels, which carry its pitch bend, pressure and timbre (CC74).
+#[derive(Clone, Debug)]
+pub struct MpeZone {
+    /// 0 for the lower zone, 15 for the upper one.
+    pub manager: u8,
+    pub members: RangeInclusive<u8>
+}
+
+/// Finds the zones configured in each track of the file, so that a zone
+/// only applies to the track that sets it up. The last configuration of
+/// each zone is used and a zone with no member channels is left out.
+pub fn detect(midi: &Smf) -> Vec<Vec<MpeZone>> {
+    midi.tracks.iter().map(detect_track).collect()
+}
+
+fn detect_track(track: &Track) -> Vec<MpeZone> {
+    // The member count of the lower and the upper zone.
+    let mut counts: [Option<u8>; 2] = [None, None];
+    // The selected RPN of each channel, MSB and LSB.
+    let mut rpns = [(127u8, 127u8); 16];
+
+    for event in track {
+        let TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::Controller { controller, value }
+        } = event.kind else {
+            continue;
+        };
+
+        let (channel, value) = (channel.as_int(), value.as_int());
+        let rpn = &mut rpns[channel as usize];
+
+        match controller.as_int() {
+            101 => rpn.0 = value,
+            100 => rpn.1 = value,
+            6 if *rpn == (0, 6) => match channel {
+                0 => counts[0] = Some(value.min(15)),
+                15 => counts[1] = Some(value.min(15)),
+                _ => { }
+            },
+            _ => { }
+        }
+    }
+
+    let mut zones = Vec::new();
+
+    if let Some(count @ 1..) = counts[0] {
+        zones.push(MpeZone {
+            manager: 0,
+            members: 1..=count
+        });
+    }
+
+    if let Some(count @ 1..) = counts[1] {
+        // The lower zone keeps the channels that both zones claim.
+        let first = (15 - count).max(zones.first().map_or(1, |x| x.members.end() + 1));
+
+        if first < 15 {
+            zones.push(MpeZone {
+                manager: 15,
+                members: first..=14
+            });
+        }
+    }
+
+    zones
+}
+
+/// The channel that stands for the whole zone when the channel is one of
+/// its members, the channel itself otherwise.
+#[inline]
+pub fn instrument(zones: &[MpeZone], channel: u8) -> u8 {
+    zones.iter()
+        .find(|x| x.members.contains(&channel))
+        .map_or(channel, |x| x.manager)
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Header, Format, Timing, TrackEvent, num::{u4, u7, u15, u28}};
+
+    use super::*;
+
+    fn zones(configurations: &[(u8, u8)]) -> Vec<MpeZone> {
+        let controller = |channel, controller, value| TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Midi {
+                channel: u4::new(channel),
+                message: MidiMessage::Controller {
+                    controller: u7::new(controller),
+                    value: u7::new(value)
+                }
+            }
+        };
+
+        let mut midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        midi.tracks.push(configurations.iter()
+            .flat_map(|&(channel, count)| [
+                controller(channel, 101, 0),
+                controller(channel, 100, 6),
+                controller(channel, 6, count)
+            ])
+            .collect());
+
+        detect(&midi).remove(0)
+    }
+
+    fn members(zones: &[MpeZone]) -> Vec<(u8, RangeInclusive<u8>)> {
+        zones.iter().map(|x| (x.manager, x.members.clone())).collect()
+    }
+
+    #[test]
+    fn detects_the_configured_zones() {
+        assert_eq!(members(&zones(&[(0, 15)])), [(0, 1..=15)]);
+        assert_eq!(members(&zones(&[(0, 7), (15, 7)])), [(0, 1..=7), (15, 8..=14)]);
+        // The last configuration is used and empty zones are left out.
+        assert_eq!(members(&zones(&[(0, 7), (15, 3), (0, 0)])), [(15, 12..=14)]);
+        // Only the RPN 6 on a manager channel configures a zone.
+        assert!(zones(&[(3, 7)]).is_empty());
+    }
+
+    #[test]
+    fn gives_the_channels_that_both_zones_claim_to_the_lower_one() {
+        let zones = zones(&[(0, 10), (15, 10)]);
+
+        assert_eq!(members(&zones), [(0, 1..=10), (15, 11..=14)]);
+        assert_eq!(instrument(&zones, 10), 0);
+        assert_eq!(instrument(&zones, 11), 15);
+        assert_eq!(instrument(&zones, 0), 0);
+    }
+}
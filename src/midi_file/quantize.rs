@@ -0,0 +1,180 @@
+use midly::{TrackEventKind, MidiMessage};
+use nohash_hasher::IntMap;
+
+use super::{
+    mpe::{self, MpeZone},
+    events::{Events, pair_notes}
+};
+
+/// Moves the hits of a track towards a grid of the beat. The NoteOffs move
+/// along with their NoteOns so that the notes keep their length.
+#[derive(Clone, Copy, Debug)]
+pub struct Quantize {
+    /// The number of grid lines in a beat, 4 for sixteenths and 3 for
+    /// eighth note triplets.
+    pub division: u16,
+    /// How far the hits are moved towards their grid line, in percent.
+    pub strength: u8,
+    /// Delays every second grid line by up to half a step, in percent.
+    pub swing: u8,
+    /// Hits further from their grid line than this percentage of half a
+    /// step are left alone.
+    pub window: u8
+}
+
+/// Returns the number of hits that were moved. The other messages on the
+/// member channel of an MPE note, like its pitch bend and pressure, move
+/// with the note they lead up to or are sent during.
+pub fn apply(
+    events: &mut Events,
+    quantize: &Quantize,
+    ticks_per_beat: u16,
+    zones: &[MpeZone]
+) -> usize {
+    let step = ticks_per_beat as f64 / quantize.division.max(1) as f64;
+    let (strength, swing, window) = (
+        quantize.strength.min(100) as f64 / 100.0,
+        quantize.swing.min(100) as f64 / 100.0,
+        quantize.window.min(100) as f64 / 100.0
+    );
+
+    // The end and the shift of the notes on each member channel, in order.
+    let mut members: IntMap<u8, Vec<(u64, i64)>> = IntMap::default();
+    let mut moved = 0;
+
+    for note in pair_notes(events) {
+        let start = note.start as f64;
+
+        // The grid lines come in pairs, the second of which swings.
+        let pair = (start / (step * 2.0)).floor() * step * 2.0;
+        let line = [pair, pair + step * (1.0 + swing / 2.0), pair + step * 2.0]
+            .into_iter()
+            .min_by(|a, b| (a - start).abs().total_cmp(&(b - start).abs()))
+            .unwrap();
+
+        let new_start = match (line - start).abs() > step / 2.0 * window {
+            true => note.start,
+            false => (start + (line - start) * strength).round().max(0.0) as u64
+        };
+
+        if mpe::instrument(zones, note.channel) != note.channel {
+            members.entry(note.channel)
+                .or_default()
+                .push((note.end.unwrap_or(u64::MAX), new_start as i64 - note.start as i64));
+        }
+
+        if new_start == note.start {
+            continue;
+        }
+
+        events[note.on].0 = new_start;
+
+        if let (Some(off), Some(end)) = (note.off, note.end) {
+            events[off].0 = new_start + (end - note.start);
+        }
+
+        moved += 1;
+    }
+
+    for (tick, kind) in events.iter_mut() {
+        let TrackEventKind::Midi { channel, message } = kind else {
+            continue;
+        };
+
+        if matches!(message, MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. }) {
+            continue;
+        }
+
+        let shift = members.get(&channel.as_int())
+            .and_then(|x| x.iter().find(|(end, _)| *end >= *tick))
+            .map_or(0, |x| x.1);
+
+        *tick = tick.saturating_add_signed(shift);
+    }
+
+    if moved > 0 {
+        events.sort_by_key(|x| x.0);
+    }
+
+    moved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_file::events::{note_on, note_off};
+
+    fn quantized(starts: &[u64], quantize: Quantize) -> (usize, Vec<(u64, Option<u64>)>) {
+        let mut events = Vec::new();
+
+        for (i, &start) in starts.iter().enumerate() {
+            events.push((start, note_on(0, 60 + i as u8, 100)));
+            events.push((start + 50, note_off(0, 60 + i as u8)));
+        }
+
+        let moved = apply(&mut events, &quantize, 480, &[]);
+        let mut notes: Vec<_> = pair_notes(&events).iter().map(|x| (x.start, x.end)).collect();
+        notes.sort();
+
+        (moved, notes)
+    }
+
+    #[test]
+    fn snaps_to_the_grid() {
+        let quantize = Quantize { division: 4, strength: 100, swing: 0, window: 100 };
+        let (moved, notes) = quantized(&[10, 110, 250, 355], quantize);
+
+        assert_eq!(moved, 4);
+        assert_eq!(notes, [(0, Some(50)), (120, Some(170)), (240, Some(290)), (360, Some(410))]);
+    }
+
+    #[test]
+    fn moves_by_the_strength() {
+        let quantize = Quantize { division: 4, strength: 50, swing: 0, window: 100 };
+        let (moved, notes) = quantized(&[20, 100], quantize);
+
+        assert_eq!(moved, 2);
+        assert_eq!(notes, [(10, Some(60)), (110, Some(160))]);
+    }
+
+    #[test]
+    fn swings_every_second_line() {
+        // The second sixteenth moves from 120 to 150 at 50 % swing.
+        let quantize = Quantize { division: 4, strength: 100, swing: 50, window: 100 };
+        let (moved, notes) = quantized(&[5, 140, 245, 370], quantize);
+
+        assert_eq!(moved, 4);
+        assert_eq!(notes, [(0, Some(50)), (150, Some(200)), (240, Some(290)), (390, Some(440))]);
+    }
+
+    #[test]
+    fn leaves_hits_outside_the_window() {
+        // Half a step is 60 ticks, so the window reaches 30 ticks.
+        let quantize = Quantize { division: 4, strength: 100, swing: 0, window: 50 };
+        let (moved, notes) = quantized(&[20, 160, 240], quantize);
+
+        assert_eq!(moved, 1);
+        assert_eq!(notes, [(0, Some(50)), (160, Some(210)), (240, Some(290))]);
+    }
+
+    #[test]
+    fn moves_member_expression_with_its_note() {
+        let zones = [MpeZone { manager: 0, members: 1..=15 }];
+        let mut events = vec![
+            (8, TrackEventKind::Midi {
+                channel: 1.into(),
+                message: MidiMessage::ChannelAftertouch { vel: 64.into() }
+            }),
+            (10, note_on(1, 60, 100)),
+            (60, note_off(1, 60))
+        ];
+
+        let quantize = Quantize { division: 4, strength: 100, swing: 0, window: 100 };
+        assert_eq!(apply(&mut events, &quantize, 480, &zones), 1);
+
+        assert_eq!(events.iter().map(|x| x.0).collect::<Vec<_>>(), [0, 0, 50]);
+        assert!(matches!(events[0].1, TrackEventKind::Midi {
+            message: MidiMessage::ChannelAftertouch { .. }, ..
+        }));
+    }
+}
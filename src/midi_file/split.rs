@@ -0,0 +1,171 @@
+use std::ops::RangeInclusive;
+
+use midly::{TrackEventKind, MidiMessage, num::{u4, u7}};
+
+use super::{
+    with_key,
+    events::Events
+};
+
+/// Splits the notes of a keyboard track between a lower and an upper part.
+/// The keys of the parts are output keys, since the track is split after
+/// its notes have been mapped. Keys that both parts contain are layered and
+/// keys that neither part contains are dropped.
+#[derive(Clone, Debug)]
+pub struct Split {
+    pub lower: SplitPart,
+    pub upper: SplitPart
+}
+
+#[derive(Clone, Debug)]
+pub struct SplitPart {
+    pub keys: RangeInclusive<u8>,
+    /// Moves the notes to this channel (0 - 15) when set.
+    pub channel: Option<u8>,
+    /// Notes moved outside of the MIDI range are dropped.
+    pub transpose: i8,
+    /// Moves the notes to a new track at the end of the file.
+    pub new_track: bool
+}
+
+/// Moves the notes of the track to the parts. The other channel messages,
+/// like the sustain pedal, are kept and copied to every part that plays on
+/// another channel or track. Returns the events of the parts that get a
+/// track of their own.
+pub fn apply<'a>(events: &mut Events<'a>, split: &Split) -> Vec<Events<'a>> {
+    let parts = [&split.lower, &split.upper];
+    let mut tracks: Vec<Events> = vec![Vec::new(); parts.len()];
+    let mut result = Vec::with_capacity(events.len());
+
+    for (tick, kind) in events.drain(..) {
+        let TrackEventKind::Midi { channel, message } = kind else {
+            result.push((tick, kind));
+            continue;
+        };
+
+        let key = match message {
+            MidiMessage::NoteOn { key, .. } |
+            MidiMessage::NoteOff { key, .. } |
+            MidiMessage::Aftertouch { key, .. } => Some(key.as_int()),
+            _ => None
+        };
+
+        if key.is_none() {
+            result.push((tick, kind));
+        }
+
+        // The channels the message was copied to in this track.
+        let mut copied = vec![channel];
+
+        for (i, part) in parts.iter().enumerate() {
+            let to = part.channel.map_or(channel, u4::from_int_lossy);
+
+            let message = match key {
+                Some(key) if part.keys.contains(&key) => {
+                    match key.checked_add_signed(part.transpose).filter(|x| *x < 128) {
+                        Some(key) => with_key(message, u7::new(key)),
+                        None => continue
+                    }
+                },
+                Some(_) => continue,
+                None if part.new_track => message,
+                None if copied.contains(&to) => continue,
+                None => {
+                    copied.push(to);
+                    message
+                }
+            };
+
+            let kind = TrackEventKind::Midi {
+                channel: to,
+                message
+            };
+
+            match part.new_track {
+                true => tracks[i].push((tick, kind)),
+                false => result.push((tick, kind))
+            }
+        }
+    }
+
+    *events = result;
+
+    tracks.into_iter()
+        .zip(parts)
+        .filter(|(_, part)| part.new_track)
+        .map(|(events, _)| events)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_file::events::{note_on, note_off};
+
+    fn part(keys: RangeInclusive<u8>, channel: Option<u8>) -> SplitPart {
+        SplitPart {
+            keys,
+            channel,
+            transpose: 0,
+            new_track: false
+        }
+    }
+
+    fn sustain<'a>(channel: u8) -> TrackEventKind<'a> {
+        TrackEventKind::Midi {
+            channel: u4::new(channel),
+            message: MidiMessage::Controller { controller: u7::new(64), value: u7::new(127) }
+        }
+    }
+
+    #[test]
+    fn moves_notes_to_their_part() {
+        let split = Split {
+            lower: part(0..=59, Some(1)),
+            upper: SplitPart { transpose: 12, ..part(55..=120, None) }
+        };
+        let mut events = vec![
+            (0, sustain(0)),
+            (0, note_on(0, 40, 100)),
+            (0, note_on(0, 57, 100)),
+            (0, note_on(0, 121, 100)),
+            (10, note_off(0, 40)),
+            (10, note_off(0, 57)),
+            (10, note_off(0, 121))
+        ];
+
+        // Layered keys are played by both parts and keys outside of both are dropped.
+        assert!(apply(&mut events, &split).is_empty());
+        assert_eq!(events, [
+            (0, sustain(0)),
+            (0, sustain(1)),
+            (0, note_on(1, 40, 100)),
+            (0, note_on(1, 57, 100)),
+            (0, note_on(0, 69, 100)),
+            (10, note_off(1, 40)),
+            (10, note_off(1, 57)),
+            (10, note_off(0, 69))
+        ]);
+    }
+
+    #[test]
+    fn moves_parts_to_new_tracks() {
+        let split = Split {
+            lower: part(0..=59, None),
+            upper: SplitPart { new_track: true, transpose: 27, ..part(60..=127, None) }
+        };
+        let mut events = vec![
+            (0, sustain(0)),
+            (0, note_on(0, 40, 100)),
+            (0, note_on(0, 100, 100)),
+            (0, note_on(0, 101, 100))
+        ];
+
+        // Notes moved outside of the MIDI range are dropped.
+        assert_eq!(apply(&mut events, &split), [vec![
+            (0, sustain(0)),
+            (0, note_on(0, 127, 100))
+        ]]);
+        assert_eq!(events, [(0, sustain(0)), (0, note_on(0, 40, 100))]);
+    }
+}
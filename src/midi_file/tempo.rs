@@ -0,0 +1,181 @@
+use midly::{Smf, Timing, TrackEventKind, MetaMessage};
+
+/// The default tempo of 120 BPM in microseconds per beat.
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// A length of time given either in ticks or in milliseconds.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Span {
+    Ticks(u64),
+    Millis(f64)
+}
+
+/// Converts between ticks and milliseconds, taking every tempo change
+/// in the file into account.
+#[derive(Debug)]
+pub struct TempoMap {
+    ticks_per_beat: Option<f64>,
+    ticks_per_second: f64,
+    /// The tick at which each tempo starts, the tempo in microseconds
+    /// per beat and the time in milliseconds at that tick.
+    changes: Vec<(u64, u32, f64)>
+}
+
+impl TempoMap {
+    pub fn new(midi: &Smf) -> Self {
+        let (ticks_per_beat, ticks_per_second) = match midi.header.timing {
+            Timing::Metrical(tpb) => (Some(tpb.as_int().max(1) as f64), 0.0),
+            Timing::Timecode(fps, subframe) =>
+                (None, fps.as_f32() as f64 * subframe as f64)
+        };
+
+        let mut tempos = vec![(0, DEFAULT_TEMPO)];
+
+        for track in &midi.tracks {
+            let mut tick = 0;
+
+            for event in track {
+                tick += event.delta.as_int() as u64;
+
+                if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+                    tempos.push((tick, tempo.as_int()));
+                }
+            }
+        }
+
+        // Stable, so a tempo at tick 0 replaces the default one.
+        tempos.sort_by_key(|x| x.0);
+
+        let mut changes: Vec<(u64, u32, f64)> = Vec::with_capacity(tempos.len());
+
+        for (tick, tempo) in tempos {
+            let ms = match changes.last() {
+                Some(&(start, last_tempo, ms)) => {
+                    let tpb = ticks_per_beat.unwrap_or(1.0);
+                    ms + (tick - start) as f64 * last_tempo as f64 / tpb / 1000.0
+                },
+                None => 0.0
+            };
+
+            if changes.last().map_or(false, |x| x.0 == tick) {
+                changes.pop();
+            }
+
+            changes.push((tick, tempo, ms));
+        }
+
+        Self {
+            ticks_per_beat,
+            ticks_per_second,
+            changes
+        }
+    }
+
+    pub fn ms(&self, tick: u64) -> f64 {
+        let Some(tpb) = self.ticks_per_beat else {
+            return tick as f64 * 1000.0 / self.ticks_per_second;
+        };
+
+        let index = self.changes.partition_point(|x| x.0 <= tick) - 1;
+        let (start, tempo, ms) = self.changes[index];
+
+        ms + (tick - start) as f64 * tempo as f64 / tpb / 1000.0
+    }
+
+    pub fn tick(&self, ms: f64) -> u64 {
+        let ms = ms.max(0.0);
+
+        let Some(tpb) = self.ticks_per_beat else {
+            return (ms * self.ticks_per_second / 1000.0).round() as u64;
+        };
+
+        let index = self.changes.partition_point(|x| x.2 <= ms).max(1) - 1;
+        let (start, tempo, start_ms) = self.changes[index];
+
+        start + ((ms - start_ms) * 1000.0 * tpb / tempo as f64).round() as u64
+    }
+
+    /// The number of ticks the span covers when it starts at the given tick.
+    pub fn ticks(&self, at: u64, span: Span) -> u64 {
+        match span {
+            Span::Ticks(ticks) => ticks,
+            Span::Millis(ms) => self.tick(self.ms(at) + ms).saturating_sub(at)
+        }
+    }
+
+    /// The number of ticks the span covers when it ends at the given tick.
+    pub fn ticks_before(&self, at: u64, span: Span) -> u64 {
+        match span {
+            Span::Ticks(ticks) => ticks,
+            Span::Millis(ms) => at.saturating_sub(self.tick(self.ms(at) - ms))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Smf, Header, Format, Timing, TrackEvent, TrackEventKind, MetaMessage, num::{u15, u24, u28}};
+
+    use super::*;
+
+    /// 480 ticks per beat at 120 BPM, then 240 BPM from the third beat on.
+    fn tempo_map() -> TempoMap {
+        let mut midi = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        let tempo = |delta, tempo| TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo)))
+        };
+
+        midi.tracks.push(vec![tempo(0, 500_000), tempo(960, 250_000)]);
+
+        TempoMap::new(&midi)
+    }
+
+    #[test]
+    fn converts_across_tempo_changes() {
+        let tempo = tempo_map();
+
+        assert_eq!(tempo.ms(480), 500.0);
+        assert_eq!(tempo.ms(960), 1000.0);
+        assert_eq!(tempo.ms(1440), 1250.0);
+        assert_eq!(tempo.tick(1250.0), 1440);
+    }
+
+    #[test]
+    fn round_trips_ticks() {
+        let tempo = tempo_map();
+
+        for tick in [0, 1, 479, 959, 960, 961, 1440, 10_000] {
+            assert_eq!(tempo.tick(tempo.ms(tick)), tick);
+        }
+    }
+
+    #[test]
+    fn measures_spans_over_a_tempo_change() {
+        let tempo = tempo_map();
+
+        assert_eq!(tempo.ticks(720, Span::Millis(500.0)), 720);
+        assert_eq!(tempo.ticks(720, Span::Ticks(30)), 30);
+    }
+
+    #[test]
+    fn uses_timecode() {
+        let midi = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Timecode(midly::Fps::Fps25, 40)
+        ));
+        let tempo = TempoMap::new(&midi);
+
+        assert_eq!(tempo.ms(1000), 1000.0);
+        assert_eq!(tempo.tick(500.0), 500);
+    }
+
+    #[test]
+    fn measures_spans_that_end_at_a_tick() {
+        let tempo = tempo_map();
+
+        assert_eq!(tempo.ticks_before(1440, Span::Millis(500.0)), 720);
+        assert_eq!(tempo.ticks_before(480, Span::Millis(1000.0)), 480);
+        assert_eq!(tempo.ticks_before(1440, Span::Ticks(30)), 30);
+    }
+}
@@ -0,0 +1,547 @@
+use midly::{Smf, Header, num::u15};
+
+use super::*;
+use super::events::{note_on, note_off};
+
+fn tempo() -> TempoMap {
+    TempoMap::new(&Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480)))))
+}
+
+fn output(note: u8) -> Output {
+    Output {
+        note: wmidi::Note::from_u8_lossy(note),
+        channel: None
+    }
+}
+
+fn target(kind: TargetKind) -> Target {
+    Target {
+        kind,
+        velocity: None,
+        generator: None
+    }
+}
+
+/// Maps the input keys to the given targets for every velocity.
+fn mapping(inputs: Vec<(u16, Vec<Target>)>) -> Mapping {
+    Mapping {
+        track: 0,
+        map: inputs.into_iter()
+            .map(|(key, targets)| (key, vec![Zone { velocity: 1..=127, targets }]))
+            .collect(),
+        collisions: None,
+        unmapped: UnmappedPolicy::Keep,
+        controllers: IntMap::default(),
+        programs: Vec::new(),
+        rules: Vec::new(),
+        scale: None,
+        chords: None,
+        articulations: Vec::new(),
+        quantize: None,
+        split: None,
+        dynamics: None,
+        retune: None
+    }
+}
+
+fn mapped<'a>(events: Events<'a>, mapping: &Mapping) -> Events<'a> {
+    map_track(events, mapping, &tempo(), &[])
+}
+
+#[test]
+fn maps_notes_by_channel() {
+    let mapping = mapping(vec![
+        (note_key(9, 38), vec![target(TargetKind::Note(output(40)))]),
+        (note_key(1, 38), vec![target(TargetKind::Note(output(50)))])
+    ]);
+    let events = vec![
+        (0, note_on(9, 38, 100)),
+        (0, note_on(1, 38, 90)),
+        (0, note_on(2, 38, 80)),
+        (10, note_off(9, 38)),
+        (10, note_off(1, 38)),
+        (10, note_off(2, 38))
+    ];
+
+    assert_eq!(mapped(events, &mapping), [
+        (0, note_on(9, 40, 100)),
+        (0, note_on(1, 50, 90)),
+        (0, note_on(2, 38, 80)),
+        (10, note_off(9, 40)),
+        (10, note_off(1, 50)),
+        (10, note_off(2, 38))
+    ]);
+}
+
+#[test]
+fn layers_outputs() {
+    let mapping = mapping(vec![(note_key(9, 36), vec![
+        target(TargetKind::Note(output(35))),
+        target(TargetKind::Note(output(36)))
+    ])]);
+    let events = vec![(0, note_on(9, 36, 100)), (10, note_off(9, 36))];
+
+    assert_eq!(mapped(events, &mapping), [
+        (0, note_on(9, 35, 100)),
+        (0, note_on(9, 36, 100)),
+        (10, note_off(9, 35)),
+        (10, note_off(9, 36))
+    ]);
+}
+
+#[test]
+fn transforms_velocities() {
+    let linear = VelocityTransform::Linear { scale: 0.5, offset: 10 };
+    assert_eq!(linear.apply(100), 60);
+    assert_eq!(VelocityTransform::Linear { scale: 2.0, offset: 0 }.apply(100), 127);
+    assert_eq!(VelocityTransform::Linear { scale: 1.0, offset: -50 }.apply(20), 1);
+
+    let clamp = VelocityTransform::Clamp { min: 40, max: 90 };
+    assert_eq!((clamp.apply(20), clamp.apply(60), clamp.apply(100)), (40, 60, 90));
+    assert_eq!(VelocityTransform::Clamp { min: 90, max: 40 }.apply(20), 90);
+
+    assert_eq!(VelocityTransform::Curve(2.0).apply(64), 32);
+    assert_eq!(VelocityTransform::Curve(0.5).apply(32), 64);
+    assert_eq!(VelocityTransform::Fixed(0).apply(100), 1);
+}
+
+#[test]
+fn transforms_the_velocity_of_each_target() {
+    let mapping = mapping(vec![(note_key(9, 36), vec![
+        Target {
+            velocity: Some(VelocityTransform::Fixed(20)),
+            ..target(TargetKind::Note(output(35)))
+        },
+        target(TargetKind::Note(output(36)))
+    ])]);
+    let events = vec![(0, note_on(9, 36, 100)), (10, note_off(9, 36))];
+
+    assert_eq!(mapped(events, &mapping)[..2], [
+        (0, note_on(9, 35, 20)),
+        (0, note_on(9, 36, 100))
+    ]);
+}
+
+/// A soft and a loud zone on the snare of the drum channel.
+fn zones(soft: RangeInclusive<u8>, loud: RangeInclusive<u8>) -> Mapping {
+    let mut mapping = mapping(Vec::new());
+    mapping.map.insert(note_key(9, 38), vec![
+        Zone { velocity: soft, targets: vec![target(TargetKind::Note(output(40)))] },
+        Zone { velocity: loud, targets: vec![target(TargetKind::Note(output(41)))] }
+    ]);
+
+    mapping
+}
+
+#[test]
+fn sends_note_offs_to_the_zone_of_their_hit() {
+    let events = vec![
+        (0, note_on(9, 38, 30)),
+        (5, note_on(9, 38, 100)),
+        (10, note_off(9, 38)),
+        (20, note_on(9, 38, 0))
+    ];
+
+    assert_eq!(mapped(events, &zones(1..=63, 64..=127)), [
+        (0, note_on(9, 40, 30)),
+        (5, note_on(9, 41, 100)),
+        (10, note_off(9, 40)),
+        (20, note_on(9, 41, 0))
+    ]);
+}
+
+#[test]
+fn validates_zones() {
+    let problem = |soft, loud| match zones(soft, loud).validate() {
+        Err(Error::Mapping(message)) => Some(message),
+        _ => None
+    };
+
+    assert_eq!(problem(1..=63, 64..=127), None);
+    assert!(problem(1..=62, 64..=127).unwrap().ends_with("leave a gap."));
+    assert!(problem(1..=64, 64..=127).unwrap().ends_with("overlap."));
+    assert!(problem(1..=63, 64..=126).unwrap().ends_with("do not reach 127."));
+    assert!(problem(1..=63, RangeInclusive::new(90, 64)).unwrap().ends_with("have an empty range."));
+}
+
+#[test]
+fn applies_the_unmapped_policy() {
+    let mut mapping = mapping(vec![(note_key(9, 36), vec![target(TargetKind::Note(output(35)))])]);
+    let events = || vec![
+        (0, note_on(9, 36, 100)),
+        (0, note_on(9, 50, 100)),
+        (10, note_off(9, 36)),
+        (10, note_off(9, 50))
+    ];
+
+    assert_eq!(mapped(events(), &mapping), [
+        (0, note_on(9, 35, 100)),
+        (0, note_on(9, 50, 100)),
+        (10, note_off(9, 35)),
+        (10, note_off(9, 50))
+    ]);
+
+    mapping.unmapped = UnmappedPolicy::Drop;
+    assert_eq!(mapped(events(), &mapping), [(0, note_on(9, 35, 100)), (10, note_off(9, 35))]);
+
+    mapping.unmapped = UnmappedPolicy::Fallback(Output { channel: Some(2), ..output(37) });
+    assert_eq!(mapped(events(), &mapping), [
+        (0, note_on(9, 35, 100)),
+        (0, note_on(2, 37, 100)),
+        (10, note_off(9, 35)),
+        (10, note_off(2, 37))
+    ]);
+}
+
+#[test]
+fn moves_notes_to_the_output_channel() {
+    let mapping = mapping(vec![(note_key(0, 60), vec![
+        target(TargetKind::Note(Output { channel: Some(3), ..output(48) }))
+    ])]);
+    let program = |channel: u8| TrackEventKind::Midi {
+        channel: channel.into(),
+        message: MidiMessage::ProgramChange { program: u7::new(5) }
+    };
+    let events = vec![
+        (0, program(0)),
+        (0, note_on(0, 60, 100)),
+        (10, note_off(0, 60))
+    ];
+
+    assert_eq!(mapped(events, &mapping), [
+        (0, program(0)),
+        (0, note_on(3, 48, 100)),
+        (10, note_off(3, 48))
+    ]);
+}
+
+fn aftertouch<'a>(channel: u8, key: u8, vel: u8) -> TrackEventKind<'a> {
+    TrackEventKind::Midi {
+        channel: channel.into(),
+        message: MidiMessage::Aftertouch { key: u7::new(key), vel: u7::new(vel) }
+    }
+}
+
+#[test]
+fn sends_aftertouch_to_the_zone_of_its_note() {
+    let events = vec![
+        (0, aftertouch(9, 38, 10)),
+        (0, note_on(9, 38, 100)),
+        (5, aftertouch(9, 38, 20)),
+        (10, note_off(9, 38)),
+        // A choke after the cymbal has ended.
+        (20, aftertouch(9, 38, 127))
+    ];
+
+    assert_eq!(mapped(events, &zones(1..=63, 64..=127)), [
+        (0, aftertouch(9, 40, 10)),
+        (0, note_on(9, 41, 100)),
+        (5, aftertouch(9, 41, 20)),
+        (10, note_off(9, 41)),
+        (20, aftertouch(9, 41, 127))
+    ]);
+}
+
+fn controller<'a>(channel: u8, controller: u8, value: u8) -> TrackEventKind<'a> {
+    TrackEventKind::Midi {
+        channel: channel.into(),
+        message: MidiMessage::Controller {
+            controller: u7::new(controller),
+            value: u7::new(value)
+        }
+    }
+}
+
+#[test]
+fn remaps_controllers() {
+    let mut mapping = mapping(Vec::new());
+    mapping.controllers.insert(4, ControllerTarget::Controller(11));
+    mapping.controllers.insert(1, ControllerTarget::Drop);
+
+    let events = vec![
+        (0, controller(9, 4, 10)),
+        (0, controller(9, 1, 20)),
+        (5, controller(9, 7, 30))
+    ];
+
+    assert_eq!(mapped(events, &mapping), [(0, controller(9, 11, 10)), (5, controller(9, 7, 30))]);
+}
+
+fn program<'a>(channel: u8, program: u8) -> TrackEventKind<'a> {
+    TrackEventKind::Midi {
+        channel: channel.into(),
+        message: MidiMessage::ProgramChange { program: u7::new(program) }
+    }
+}
+
+#[test]
+fn replaces_the_bank_selects_of_remapped_programs() {
+    let mut mapping = mapping(Vec::new());
+    mapping.programs.push((
+        Patch { bank_msb: Some(1), bank_lsb: Some(2), program: 3 },
+        Patch { bank_msb: Some(8), bank_lsb: None, program: 9 }
+    ));
+
+    let events = vec![
+        (0, controller(0, 0, 1)),
+        (0, controller(0, 32, 2)),
+        (0, program(0, 3)),
+        (10, controller(0, 0, 5)),
+        (10, program(0, 3)),
+        (20, program(1, 3))
+    ];
+
+    assert_eq!(mapped(events, &mapping), [
+        (0, controller(0, 0, 8)),
+        (0, program(0, 9)),
+        (10, controller(0, 0, 5)),
+        (10, program(0, 3)),
+        (20, program(1, 3))
+    ]);
+}
+
+#[test]
+fn converts_controllers_to_notes() {
+    let mut mapping = mapping(Vec::new());
+    mapping.controllers.insert(64, ControllerTarget::Notes(vec![
+        Threshold { value: 64, output: output(50), velocity: None },
+        Threshold { value: 100, output: output(51), velocity: Some(90) }
+    ]));
+
+    let events = vec![
+        (0, controller(0, 64, 80)),
+        (5, controller(0, 64, 120)),
+        (10, controller(0, 64, 70)),
+        (15, controller(0, 64, 10)),
+        (20, controller(0, 64, 100)),
+        (30, TrackEventKind::Meta(MetaMessage::EndOfTrack))
+    ];
+
+    // The notes that are still on end with the track.
+    assert_eq!(mapped(events, &mapping), [
+        (0, note_on(0, 50, 80)),
+        (5, note_on(0, 51, 90)),
+        (10, note_off(0, 51)),
+        (15, note_off(0, 50)),
+        (20, note_on(0, 50, 100)),
+        (20, note_on(0, 51, 90)),
+        (30, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        (30, note_off(0, 50)),
+        (30, note_off(0, 51))
+    ]);
+}
+
+#[test]
+fn converts_notes_to_controllers() {
+    let mapping = mapping(vec![(note_key(0, 40), vec![
+        target(TargetKind::Controller { controller: 4, value: None }),
+        target(TargetKind::Controller { controller: 5, value: Some(7) })
+    ])]);
+    let events = vec![(0, note_on(0, 40, 90)), (10, note_off(0, 40))];
+
+    assert_eq!(mapped(events, &mapping), [(0, controller(0, 4, 90)), (0, controller(0, 5, 7))]);
+}
+
+#[test]
+fn applies_rules_in_order() {
+    let rules = [
+        Rule::Transpose(12),
+        Rule::Shift { from: 36..=51, to: 60 },
+        Rule::PitchClass { from: 1, to: 2 }
+    ];
+
+    // The range shifts and pitch classes come before the transposition.
+    assert_eq!(apply_rules(&rules, None, 40), Some(64));
+    assert_eq!(apply_rules(&rules, None, 61), Some(62));
+    assert_eq!(apply_rules(&rules, None, 70), Some(82));
+    // The first rule that matches wins, even when it leaves the MIDI range.
+    assert_eq!(apply_rules(&rules, None, 120), None);
+    assert_eq!(apply_rules(&[Rule::Shift { from: 100..=127, to: 120 }, Rule::Transpose(-1)], None, 110), None);
+    assert_eq!(apply_rules(&[], None, 40), None);
+}
+
+#[test]
+fn maps_unmapped_notes_by_the_rules() {
+    let mut mapping = mapping(vec![(note_key(0, 60), vec![target(TargetKind::Note(output(72)))])]);
+    mapping.rules.push(Rule::Transpose(-12));
+
+    let events = vec![
+        (0, note_on(0, 60, 100)),
+        (0, note_on(0, 64, 100)),
+        (10, note_off(0, 60)),
+        (10, note_off(0, 64))
+    ];
+
+    assert_eq!(mapped(events, &mapping), [
+        (0, note_on(0, 72, 100)),
+        (0, note_on(0, 52, 100)),
+        (10, note_off(0, 72)),
+        (10, note_off(0, 52))
+    ]);
+}
+
+#[test]
+fn snaps_to_the_scale() {
+    // C major.
+    let scale = |rounding| Scale { pitch_classes: 0b1010_1011_0101, rounding };
+
+    assert_eq!(scale(Rounding::Nearest).snap(60), 60);
+    assert_eq!(scale(Rounding::Nearest).snap(61), 60);
+    assert_eq!(scale(Rounding::Up).snap(61), 62);
+    assert_eq!(scale(Rounding::Down).snap(63), 62);
+    // Only one direction is left at the edges of the MIDI range.
+    assert_eq!(scale(Rounding::Down).snap(0), 0);
+    assert_eq!(Scale { pitch_classes: 1, rounding: Rounding::Up }.snap(121), 120);
+    assert_eq!(Scale { pitch_classes: 0, rounding: Rounding::Nearest }.snap(61), 61);
+
+    let rules = [Rule::Transpose(1)];
+    assert_eq!(apply_rules(&rules, Some(&scale(Rounding::Up)), 60), Some(62));
+    assert_eq!(apply_rules(&[], Some(&scale(Rounding::Up)), 64), Some(64));
+}
+
+#[test]
+fn keeps_notes_in_the_scale() {
+    let mut mapping = mapping(vec![]);
+    mapping.unmapped = UnmappedPolicy::Drop;
+    mapping.scale = Some(Scale { pitch_classes: 0b1010_1011_0101, rounding: Rounding::Up });
+
+    let events = vec![
+        (0, note_on(0, 60, 100)),
+        (0, note_on(0, 61, 100)),
+        (10, note_off(0, 60)),
+        (10, note_off(0, 61))
+    ];
+
+    assert_eq!(mapped(events, &mapping), [
+        (0, note_on(0, 60, 100)),
+        (0, note_on(0, 62, 100)),
+        (10, note_off(0, 60)),
+        (10, note_off(0, 62))
+    ]);
+}
+
+#[test]
+fn switches_outputs_by_the_controller() {
+    let pedal = mapping(vec![(note_key(9, 44), vec![target(TargetKind::Switch {
+        controller: 4,
+        outputs: vec![(0, output(42)), (64, output(46))]
+    })])]);
+
+    let events = vec![
+        (0, note_on(9, 44, 100)),
+        (10, controller(9, 4, 100)),
+        (10, controller(8, 4, 0)),
+        (15, note_off(9, 44)),
+        (20, note_on(9, 44, 90)),
+        (30, note_off(9, 44))
+    ];
+
+    // The NoteOff goes to the output of its hit even after the pedal moved.
+    assert_eq!(mapped(events, &pedal), [
+        (0, note_on(9, 42, 100)),
+        (10, controller(9, 4, 100)),
+        (10, controller(8, 4, 0)),
+        (15, note_off(9, 42)),
+        (20, note_on(9, 46, 90)),
+        (30, note_off(9, 46))
+    ]);
+
+    let open = mapping(vec![(note_key(9, 44), vec![target(TargetKind::Switch {
+        controller: 4,
+        outputs: vec![(64, output(46))]
+    })])]);
+
+    // Values below the first output play nothing.
+    assert!(mapped(vec![(0, note_on(9, 44, 100)), (10, note_off(9, 44))], &open).is_empty());
+}
+
+#[test]
+fn picks_outputs_from_a_pool() {
+    let pool = |strategy| mapping(vec![(note_key(9, 38), vec![target(TargetKind::Pool {
+        outputs: vec![output(40), output(41), output(42)],
+        strategy
+    })])]);
+
+    let events: Events = (0..4)
+        .flat_map(|i| [(i * 10, note_on(9, 38, 100)), (i * 10 + 5, note_off(9, 38))])
+        .collect();
+
+    assert_eq!(mapped(events.clone(), &pool(PoolStrategy::Cycle)), [
+        (0, note_on(9, 40, 100)),
+        (5, note_off(9, 40)),
+        (10, note_on(9, 41, 100)),
+        (15, note_off(9, 41)),
+        (20, note_on(9, 42, 100)),
+        (25, note_off(9, 42)),
+        (30, note_on(9, 40, 100)),
+        (35, note_off(9, 40))
+    ]);
+
+    // The same seed picks the same outputs, and every NoteOff follows its hit.
+    let random = mapped(events.clone(), &pool(PoolStrategy::Random(7)));
+    assert_eq!(random, mapped(events, &pool(PoolStrategy::Random(7))));
+
+    let keys: Vec<u8> = random.iter()
+        .map(|(_, kind)| match kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. },
+                ..
+            } => key.as_int(),
+            _ => 0
+        })
+        .collect();
+
+    assert!(keys.chunks(2).all(|x| x[0] == x[1]), "{random:?}");
+}
+
+#[test]
+fn sends_overlapping_note_offs_to_the_output_of_their_hit() {
+    let mapping = mapping(vec![(note_key(9, 38), vec![target(TargetKind::Pool {
+        outputs: vec![output(40), output(41)],
+        strategy: PoolStrategy::Cycle
+    })])]);
+
+    let events = vec![
+        (0, note_on(9, 38, 100)),
+        (10, note_on(9, 38, 90)),
+        (20, note_off(9, 38)),
+        (30, note_off(9, 38))
+    ];
+
+    assert_eq!(mapped(events, &mapping), [
+        (0, note_on(9, 40, 100)),
+        (10, note_on(9, 41, 90)),
+        (20, note_off(9, 40)),
+        (30, note_off(9, 41))
+    ]);
+}
+
+#[test]
+fn keeps_mpe_notes_on_their_member_channel() {
+    let mut mapping = mapping(vec![(note_key(0, 60), vec![
+        target(TargetKind::Note(output(72))),
+        target(TargetKind::Note(Output { channel: Some(5), ..output(84) }))
+    ])]);
+    mapping.controllers.insert(74, ControllerTarget::Controller(75));
+
+    let events = vec![
+        (0, controller(0, 74, 10)),
+        (0, controller(3, 74, 20)),
+        (0, note_on(3, 60, 100)),
+        (5, aftertouch(3, 60, 30)),
+        (10, note_off(3, 60))
+    ];
+    let zones = [MpeZone { manager: 0, members: 1..=15 }];
+
+    // The controllers on member channels carry the expression of their note.
+    assert_eq!(map_track(events, &mapping, &tempo(), &zones), [
+        (0, controller(0, 75, 10)),
+        (0, controller(3, 74, 20)),
+        (0, note_on(3, 72, 100)),
+        (0, note_on(3, 84, 100)),
+        (5, aftertouch(3, 72, 30)),
+        (5, aftertouch(3, 84, 30)),
+        (10, note_off(3, 72)),
+        (10, note_off(3, 84))
+    ]);
+}
@@ -0,0 +1,495 @@
+use std::{ops::RangeInclusive, collections::VecDeque};
+
+use midly::{TrackEventKind, MidiMessage, num::{u4, u7, u14}};
+use nohash_hasher::IntMap;
+
+use super::{
+    Result, Error,
+    note_key,
+    events::Events
+};
+
+/// The pitch of every key, read from a Scala scale and keyboard mapping.
+#[derive(Clone, Debug)]
+pub struct Tuning {
+    /// The pitch of each key in 12-TET semitones, where 69 is A4 at 440 Hz.
+    /// Keys that the keyboard mapping leaves out are not set.
+    pitches: [Option<f64>; 128],
+    /// A bulk tuning dump of the pitches without the leading 0xF0.
+    dump: Vec<u8>
+}
+
+/// Plays the notes of a track in a tuning.
+#[derive(Clone, Debug)]
+pub struct Retune {
+    pub tuning: Tuning,
+    pub mode: RetuneMode
+}
+
+#[derive(Clone, Debug)]
+pub enum RetuneMode {
+    /// Moves each note to the nearest key and bends it the rest of the way.
+    /// The notes are spread over the channels so that each one can be bent
+    /// on its own, like in MPE. Controllers, program changes and channel
+    /// pressure are copied to every channel and the pitch bends of the
+    /// track are dropped.
+    PitchBend {
+        /// The pitch bend range in semitones, sent to every channel at the
+        /// start of the track.
+        range: u8,
+        /// 0 - 15.
+        channels: RangeInclusive<u8>
+    },
+    /// Adds a MIDI Tuning Standard bulk dump of tuning program 0 at the
+    /// start of the track. The dump retunes every key on its own, so the
+    /// notes keep their keys.
+    Mts
+}
+
+/// The keyboard mapping used when a scale comes without one. Maps the
+/// scale linearly with its first degree on middle C and A4 at 440 Hz.
+const DEFAULT_KBM: KeyboardMap = KeyboardMap {
+    keys: 0..=127,
+    middle: 60,
+    reference: 69,
+    frequency: 440.0,
+    octave_degree: None,
+    map: Vec::new()
+};
+
+struct KeyboardMap {
+    keys: RangeInclusive<u8>,
+    /// The key that plays the first degree of the scale.
+    middle: u8,
+    reference: u8,
+    frequency: f64,
+    /// The degree that the mapping repeats at, the last degree of the
+    /// scale when not set.
+    octave_degree: Option<usize>,
+    /// The degree of each key in the mapping, maps the scale linearly
+    /// when empty.
+    map: Vec<Option<usize>>
+}
+
+impl Tuning {
+    /// Reads a Scala scale (`.scl`) and an optional keyboard mapping (`.kbm`).
+    pub fn from_scala(scl: &str, kbm: Option<&str>) -> Result<Self> {
+        let mut lines = scala_lines(scl);
+
+        let description = lines.next().unwrap_or("");
+        let count: usize = parse_scala(lines.next(), "Scala file", "the number of notes")?;
+
+        // The first degree is always the unison.
+        let mut degrees = vec![0.0];
+
+        for _ in 0..count {
+            let Some(line) = lines.next() else {
+                return Err(Error::Mapping(format!(
+                    "Scala file: the scale should have {count} notes."
+                )));
+            };
+
+            degrees.push(parse_pitch(line)?);
+        }
+
+        if count == 0 {
+            // A scale without notes repeats at the unison, use 12-TET instead.
+            degrees.push(1200.0);
+        }
+
+        let map = match kbm {
+            Some(kbm) => KeyboardMap::parse(kbm)?,
+            None => DEFAULT_KBM
+        };
+
+        // The period of the scale is its last degree.
+        let size = degrees.len() - 1;
+        let cents = |degree: usize| {
+            (degree / size) as f64 * degrees[size] + degrees[degree % size]
+        };
+
+        let octave = cents(map.octave_degree.unwrap_or(size));
+
+        let key_cents = |key: u8| -> Option<f64> {
+            if !map.keys.contains(&key) {
+                return None;
+            }
+
+            let offset = key as i32 - map.middle as i32;
+
+            if map.map.is_empty() {
+                let (repeat, degree) =
+                    (offset.div_euclid(size as i32), offset.rem_euclid(size as i32));
+
+                return Some(repeat as f64 * degrees[size] + degrees[degree as usize]);
+            }
+
+            let len = map.map.len() as i32;
+            let (repeat, index) = (offset.div_euclid(len), offset.rem_euclid(len));
+
+            map.map[index as usize].map(|degree| repeat as f64 * octave + cents(degree))
+        };
+
+        let Some(reference) = key_cents(map.reference) else {
+            return Err(Error::Mapping(
+                "Keyboard mapping: the reference key is not mapped.".into()
+            ));
+        };
+
+        let reference = 69.0 + 12.0 * (map.frequency / 440.0).log2() - reference / 100.0;
+
+        let mut pitches = [None; 128];
+
+        for (key, pitch) in pitches.iter_mut().enumerate() {
+            *pitch = key_cents(key as u8).map(|x| reference + x / 100.0);
+        }
+
+        Ok(Self {
+            dump: bulk_dump(description, &pitches),
+            pitches
+        })
+    }
+
+    #[inline]
+    pub fn pitch(&self, key: u8) -> Option<f64> {
+        self.pitches[key as usize & 0x7f]
+    }
+}
+
+/// Retunes the notes of the track, the keys without a pitch keep their
+/// 12-TET pitch. Returns the number of notes that were moved to another
+/// key, channel or bent.
+pub fn apply<'a>(events: &mut Events<'a>, retune: &'a Retune) -> usize {
+    let (range, channels) = match &retune.mode {
+        RetuneMode::PitchBend { range, channels } => ((*range).max(1), channels.clone()),
+        RetuneMode::Mts => {
+            // Before every other event, so the first notes are in tune.
+            events.insert(0, (0, TrackEventKind::SysEx(&retune.tuning.dump)));
+            return 0;
+        }
+    };
+
+    let channels: Vec<u8> = channels.filter(|x| *x < 16).collect();
+
+    if channels.is_empty() {
+        return 0;
+    }
+
+    let mut result = Vec::with_capacity(events.len());
+    // The channel and key each sounding note was moved to, in the order
+    // they were hit.
+    let mut active: IntMap<u16, VecDeque<(u8, u8)>> = IntMap::default();
+    // The number of sounding notes on each channel and when it was last used.
+    let mut voices = vec![(0usize, 0u64); channels.len()];
+    let mut count = 0;
+
+    for &channel in &channels {
+        // Pitch bend sensitivity (RPN 0), followed by the null RPN.
+        let rpn = [(101, 0), (100, 0), (6, range), (38, 0), (101, 127), (100, 127)];
+
+        for (controller, value) in rpn {
+            result.push((0, controller_event(channel, controller, value)));
+        }
+    }
+
+    for (tick, kind) in events.drain(..) {
+        let TrackEventKind::Midi { channel, message } = kind else {
+            result.push((tick, kind));
+            continue;
+        };
+
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                let pitch = retune.tuning.pitch(key.as_int()).unwrap_or(key.as_int() as f64);
+                let nearest = pitch.round().clamp(0.0, 127.0);
+                let bend = ((pitch - nearest) / range as f64 * 8192.0 + 8192.0)
+                    .round()
+                    .clamp(0.0, 16383.0) as u16;
+
+                // The free channel that was hit the longest ago, or the busy
+                // one when every channel is in use.
+                let voice = (0..channels.len())
+                    .min_by_key(|&i| (voices[i].0 > 0, voices[i].1))
+                    .unwrap();
+
+                voices[voice] = (voices[voice].0 + 1, tick);
+
+                let (to, new_key) = (channels[voice], nearest as u8);
+
+                if bend != 8192 || new_key != key.as_int() || to != channel.as_int() {
+                    count += 1;
+                }
+
+                result.push((tick, TrackEventKind::Midi {
+                    channel: u4::from_int_lossy(to),
+                    message: MidiMessage::PitchBend { bend: midly::PitchBend(u14::new(bend)) }
+                }));
+                result.push((tick, TrackEventKind::Midi {
+                    channel: u4::from_int_lossy(to),
+                    message: MidiMessage::NoteOn { key: u7::new(new_key), vel }
+                }));
+
+                active.entry(note_key(channel.as_int(), key.as_int()))
+                    .or_default()
+                    .push_back((to, new_key));
+            },
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } |
+            MidiMessage::Aftertouch { key, .. } => {
+                let note = active.get_mut(&note_key(channel.as_int(), key.as_int()));
+                let moved = match message {
+                    MidiMessage::Aftertouch { .. } => note.and_then(|x| x.front().copied()),
+                    _ => note.and_then(|x| x.pop_front())
+                };
+
+                let Some((to, new_key)) = moved else {
+                    result.push((tick, kind));
+                    continue;
+                };
+
+                if !matches!(message, MidiMessage::Aftertouch { .. }) {
+                    if let Some(voice) = channels.iter().position(|x| *x == to) {
+                        voices[voice].0 -= 1;
+                    }
+                }
+
+                result.push((tick, TrackEventKind::Midi {
+                    channel: u4::from_int_lossy(to),
+                    message: super::with_key(message, u7::new(new_key))
+                }));
+            },
+            MidiMessage::PitchBend { .. } => { },
+            MidiMessage::Controller { .. } |
+            MidiMessage::ProgramChange { .. } |
+            MidiMessage::ChannelAftertouch { .. } => {
+                for &to in &channels {
+                    result.push((tick, TrackEventKind::Midi {
+                        channel: u4::from_int_lossy(to),
+                        message
+                    }));
+                }
+
+                if !channels.contains(&channel.as_int()) {
+                    result.push((tick, kind));
+                }
+            }
+        }
+    }
+
+    *events = result;
+
+    count
+}
+
+#[inline]
+fn controller_event<'a>(channel: u8, controller: u8, value: u8) -> TrackEventKind<'a> {
+    TrackEventKind::Midi {
+        channel: u4::from_int_lossy(channel),
+        message: MidiMessage::Controller {
+            controller: u7::from_int_lossy(controller),
+            value: u7::from_int_lossy(value)
+        }
+    }
+}
+
+/// A bulk tuning dump (MIDI Tuning Standard) for tuning program 0, sent to
+/// every device. Keys without a pitch are left unchanged.
+fn bulk_dump(name: &str, pitches: &[Option<f64>; 128]) -> Vec<u8> {
+    let mut dump = vec![0x7e, 0x7f, 0x08, 0x01, 0x00];
+
+    let name = name.bytes().filter(|x| (0x20..0x7f).contains(x));
+    dump.extend(name.chain(std::iter::repeat(b' ')).take(16));
+
+    for pitch in pitches {
+        let Some(pitch) = pitch.filter(|x| (0.0..128.0).contains(x)) else {
+            dump.extend([0x7f, 0x7f, 0x7f]);
+            continue;
+        };
+
+        let mut semitone = pitch.floor();
+        let mut fraction = ((pitch - semitone) * 16384.0).round();
+
+        if fraction >= 16384.0 {
+            (semitone, fraction) = (semitone + 1.0, 0.0);
+        }
+
+        let (semitone, fraction) = (semitone.min(127.0) as u8, fraction as u16);
+        dump.extend([semitone, (fraction >> 7) as u8, (fraction & 0x7f) as u8]);
+    }
+
+    let checksum = dump.iter().fold(0, |sum, x| sum ^ x) & 0x7f;
+    dump.extend([checksum, 0xf7]);
+
+    dump
+}
+
+impl KeyboardMap {
+    fn parse(text: &str) -> Result<Self> {
+        let mut lines = scala_lines(text);
+        let name = "Keyboard mapping";
+
+        let size: usize = parse_scala(lines.next(), name, "the map size")?;
+        let first: u8 = parse_scala(lines.next(), name, "the first key")?;
+        let last: u8 = parse_scala(lines.next(), name, "the last key")?;
+        let middle: u8 = parse_scala(lines.next(), name, "the middle key")?;
+        let reference: u8 = parse_scala(lines.next(), name, "the reference key")?;
+        let frequency: f64 = parse_scala(lines.next(), name, "the reference frequency")?;
+        let octave_degree: usize = parse_scala(lines.next(), name, "the octave degree")?;
+
+        if [first, last, middle, reference].iter().any(|x| *x > 127) || frequency <= 0.0 {
+            return Err(Error::Mapping(format!(
+                "{name}: keys have to be in the range of 0 - 127 and the frequency above 0."
+            )));
+        }
+
+        let mut map = Vec::with_capacity(size);
+
+        for line in lines.take(size) {
+            let degree = match line {
+                "x" | "X" => None,
+                degree => Some(parse_scala(Some(degree), name, "a degree")?)
+            };
+
+            map.push(degree);
+        }
+
+        // Missing entries at the end are unmapped.
+        map.resize(size, None);
+
+        Ok(Self {
+            keys: first..=last,
+            middle,
+            reference,
+            frequency,
+            octave_degree: (octave_degree > 0).then_some(octave_degree),
+            map
+        })
+    }
+}
+
+/// The lines of a Scala file without comments, trimmed to their first word.
+/// The description line of a scale is kept whole.
+fn scala_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .filter(|x| !x.starts_with('!'))
+        .enumerate()
+        .map(|(i, x)| match i {
+            0 => x.trim(),
+            _ => x.split_whitespace().next().unwrap_or("")
+        })
+}
+
+fn parse_scala<T: std::str::FromStr>(line: Option<&str>, name: &str, what: &str) -> Result<T> {
+    line.and_then(|x| x.parse().ok()).ok_or_else(||
+        Error::Mapping(format!("{name}: expected {what} but found \"{}\".", line.unwrap_or("")))
+    )
+}
+
+/// Cents when the line has a period, a ratio otherwise.
+fn parse_pitch(line: &str) -> Result<f64> {
+    let invalid = || Error::Mapping(format!("Scala file: \"{line}\" is not a valid pitch."));
+
+    if line.contains('.') {
+        return line.parse().map_err(|_| invalid());
+    }
+
+    let (numerator, denominator) = line.split_once('/').unwrap_or((line, "1"));
+    let (numerator, denominator): (f64, f64) = (
+        numerator.parse().map_err(|_| invalid())?,
+        denominator.parse().map_err(|_| invalid())?
+    );
+
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return Err(invalid());
+    }
+
+    Ok(1200.0 * (numerator / denominator).log2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A semitone in cents, a fifth as a ratio and an octave as an integer.
+    const SCALE: &str = "! test.scl\n\
+        !\n\
+        Test scale\n \
+        3\n\
+        !\n \
+        100.0 cents\n \
+        3/2\n \
+        2\n";
+
+    fn assert_pitch(tuning: &Tuning, key: u8, pitch: Option<f64>) {
+        match (tuning.pitch(key), pitch) {
+            (Some(a), Some(b)) => assert!((a - b).abs() < 1e-6, "key {key}: {a} != {b}"),
+            (a, b) => assert_eq!(a, b, "key {key}")
+        }
+    }
+
+    #[test]
+    fn parses_ratios_and_cents() {
+        let tuning = Tuning::from_scala(SCALE, None).unwrap();
+
+        // A4 is at 440 Hz, three periods above the middle key.
+        assert_pitch(&tuning, 69, Some(69.0));
+        assert_pitch(&tuning, 60, Some(33.0));
+        assert_pitch(&tuning, 61, Some(34.0));
+        assert_pitch(&tuning, 62, Some(33.0 + 12.0 * 1.5f64.log2()));
+        assert_pitch(&tuning, 63, Some(45.0));
+        assert_pitch(&tuning, 57, Some(21.0));
+    }
+
+    #[test]
+    fn applies_a_keyboard_mapping() {
+        let kbm = "! test.kbm\n4\n0\n127\n60\n60\n261.6255653\n3\n0\n1\nx\n2\n";
+        let tuning = Tuning::from_scala(SCALE, Some(kbm)).unwrap();
+
+        assert_pitch(&tuning, 60, Some(60.0));
+        assert_pitch(&tuning, 61, Some(61.0));
+        assert_pitch(&tuning, 62, None);
+        assert_pitch(&tuning, 63, Some(60.0 + 12.0 * 1.5f64.log2()));
+        assert_pitch(&tuning, 64, Some(72.0));
+        assert_pitch(&tuning, 56, Some(48.0));
+    }
+
+    #[test]
+    fn rejects_invalid_scales() {
+        assert!(Tuning::from_scala("Short\n3\n100.0\n2/1\n", None).is_err());
+        assert!(Tuning::from_scala("Zero\n1\n3/0\n", None).is_err());
+        assert!(Tuning::from_scala("Words\nthree\n", None).is_err());
+    }
+
+    #[test]
+    fn checksums_the_bulk_dump() {
+        let mut pitches = [None; 128];
+
+        for (key, pitch) in pitches.iter_mut().enumerate() {
+            *pitch = Some(key as f64);
+        }
+
+        let dump = bulk_dump("", &pitches);
+
+        assert_eq!(dump.len(), 5 + 16 + 128 * 3 + 2);
+        assert_eq!(dump[dump.len() - 1], 0xf7);
+        // The header XORs to 0x08 and the 16 spaces and the keys to 0.
+        assert_eq!(dump[dump.len() - 2], 0x08);
+
+        let tuning = Tuning::from_scala(SCALE, None).unwrap();
+        let (data, end) = tuning.dump.split_at(tuning.dump.len() - 2);
+
+        assert_eq!(&data[5..21], b"Test scale      ");
+        assert_eq!(end[0], data.iter().fold(0, |sum, x| sum ^ x) & 0x7f);
+    }
+
+    #[test]
+    fn splits_pitches_into_semitone_and_fraction() {
+        let mut pitches = [None; 128];
+        pitches[0] = Some(60.5);
+        pitches[1] = Some(61.0 - 1e-9);
+
+        let dump = bulk_dump("", &pitches);
+
+        assert_eq!(dump[21..27], [60, 0x40, 0x00, 61, 0x00, 0x00]);
+        assert_eq!(dump[27..30], [0x7f, 0x7f, 0x7f]);
+    }
+}
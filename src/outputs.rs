@@ -1,24 +1,49 @@
+use std::collections::BTreeMap;
+
 use microui_femtovg::microui::{*, const_vec::ConstStr};
 
+use crate::{
+    midi_file::{Output, OutputSettings, ChokeGroup, Choke, NoteLength, Span},
+    Result, Error
+};
+
 const PANEL_NAME: &str = "outputs";
+const CHOKE_KINDS: [&str; 2] = ["Note off", "Controller"];
+const LENGTH_KINDS: [&str; 3] = ["Keep", "Fixed", "Minimum"];
+const LENGTH_UNITS: [&str; 2] = ["Ticks", "ms"];
 
 pub struct State {
     outputs: Vec<OutputState>,
     notes_dropdown: dropdown::State,
-    notes: Vec<String>
+    notes: Vec<String>,
+    channels: Vec<String>
 }
 
 pub enum Event {
     OutputRemoved {
         note: wmidi::Note,
         removed_index: usize
+    },
+    OutputChanged {
+        index: usize,
+        output: Output
     }
 }
 
 struct OutputState {
     note: wmidi::Note,
     note_string: String,
-    alias: ConstStr<16>
+    alias: ConstStr<16>,
+    channel_dropdown: dropdown::State,
+    channel: Option<u8>,
+    /// The number of the choke group, none when empty.
+    choke_group: ConstStr<4>,
+    choke_kind: dropdown::State,
+    choke_controller: ConstStr<4>,
+    choke_value: ConstStr<4>,
+    length_kind: dropdown::State,
+    length: ConstStr<8>,
+    length_unit: dropdown::State
 }
 
 impl State {
@@ -56,8 +81,54 @@ impl State {
     }
 
     #[inline]
-    pub fn output(&self, index: usize) -> wmidi::Note {
-        self.outputs[index].note
+    pub fn output(&self, index: usize) -> Output {
+        self.outputs[index].output()
+    }
+
+    /// Collects the note lengths and the choke groups of the outputs,
+    /// choke groups with a single output are left out.
+    pub fn settings(&self) -> Result<OutputSettings> {
+        let mut groups: BTreeMap<u32, Vec<(Output, Choke)>> = BTreeMap::new();
+        let mut lengths = Vec::new();
+
+        for state in &self.outputs {
+            if let Some(length) = state.length()? {
+                lengths.push((state.output(), length));
+            }
+
+            let group = state.choke_group.as_str().trim();
+
+            if group.is_empty() {
+                continue;
+            }
+
+            let Ok(group) = group.parse::<u32>() else {
+                return Err(Error::Mapping(format!(
+                    "The choke group \"{group}\" of {} is not a number.",
+                    state.note
+                )));
+            };
+
+            let choke = match state.choke_kind.index {
+                Some(1) => Choke::Controller {
+                    controller: state.data_byte(&state.choke_controller, "controller", None)?,
+                    value: state.data_byte(&state.choke_value, "value", Some(127))?
+                },
+                _ => Choke::NoteOff
+            };
+
+            groups.entry(group).or_default().push((state.output(), choke));
+        }
+
+        let chokes = groups.into_values()
+            .filter(|x| x.len() > 1)
+            .map(|members| ChokeGroup { members })
+            .collect();
+
+        Ok(OutputSettings {
+            chokes,
+            lengths
+        })
     }
 
     #[inline]
@@ -111,6 +182,54 @@ impl State {
             ctx.label("Alias:");
             ctx.textbox(&mut state.alias);
 
+            ctx.layout_row(&[48, 150], 0);
+            ctx.label("Channel:");
+
+            ctx.push_id(&(state as *mut OutputState));
+            if ctx.w(Dropdown::new(
+                    &mut state.channel_dropdown,
+                    &self.channels
+                ).visible_items(10)
+            ).submit {
+                let index = state.channel_dropdown.index.unwrap();
+                state.channel = index.checked_sub(1).map(|x| x as u8);
+
+                event = Some(Event::OutputChanged {
+                    index: i,
+                    output: state.output()
+                });
+            }
+
+            ctx.layout_row(&[48, 40], 0);
+            ctx.label("Choke:");
+            ctx.textbox(&mut state.choke_group);
+
+            if !state.choke_group.as_str().trim().is_empty() {
+                ctx.layout_row(&[48, 150], 0);
+                ctx.label("By:");
+                ctx.w(Dropdown::new(&mut state.choke_kind, &CHOKE_KINDS));
+
+                if state.choke_kind.index == Some(1) {
+                    ctx.layout_row(&[48, 40, 48, 40], 0);
+                    ctx.label("CC:");
+                    ctx.textbox(&mut state.choke_controller);
+                    ctx.label("Value:");
+                    ctx.textbox(&mut state.choke_value);
+                }
+            }
+
+            ctx.layout_row(&[48, 100], 0);
+            ctx.label("Length:");
+            ctx.w(Dropdown::new(&mut state.length_kind, &LENGTH_KINDS));
+
+            if state.length_kind.index.is_some_and(|x| x > 0) {
+                ctx.layout_row(&[48, 60, 60], 0);
+                ctx.label("");
+                ctx.textbox(&mut state.length);
+                ctx.w(Dropdown::new(&mut state.length_unit, &LENGTH_UNITS));
+            }
+            ctx.pop_id();
+
             ctx.layout_row(&[-1], 1);
 
             let rect = ctx.layout_next();
@@ -137,10 +256,18 @@ impl Default for State {
             notes.push(note);
         }
 
+        let mut channels = Vec::with_capacity(17);
+        channels.push("Keep".into());
+
+        for i in 1..=16 {
+            channels.push(i.to_string());
+        }
+
         Self {
             outputs: Vec::new(),
             notes_dropdown: dropdown::State::default(),
-            notes
+            notes,
+            channels
         }
     }
 }
@@ -150,7 +277,70 @@ impl OutputState {
         Self {
             note,
             note_string: note.to_string(),
-            alias: ConstStr::new()
+            alias: ConstStr::new(),
+            channel_dropdown: dropdown::State::with_selection(0),
+            channel: None,
+            choke_group: ConstStr::new(),
+            choke_kind: dropdown::State::with_selection(0),
+            choke_controller: ConstStr::new(),
+            choke_value: ConstStr::new(),
+            length_kind: dropdown::State::with_selection(0),
+            length: ConstStr::new(),
+            length_unit: dropdown::State::with_selection(0)
+        }
+    }
+
+    fn length(&self) -> Result<Option<NoteLength>> {
+        let kind = self.length_kind.index.unwrap_or(0);
+
+        if kind == 0 {
+            return Ok(None);
+        }
+
+        let text = self.length.as_str().trim();
+        let invalid = || Error::Mapping(format!(
+            "Length of {}: \"{text}\" is not a valid value.",
+            self.note
+        ));
+
+        let span = if self.length_unit.index == Some(1) {
+            match text.parse::<f64>() {
+                Ok(ms) if ms > 0.0 => Span::Millis(ms),
+                _ => return Err(invalid())
+            }
+        } else {
+            match text.parse::<u64>() {
+                Ok(ticks) if ticks > 0 => Span::Ticks(ticks),
+                _ => return Err(invalid())
+            }
+        };
+
+        match kind {
+            1 => Ok(Some(NoteLength::Fixed(span))),
+            _ => Ok(Some(NoteLength::Minimum(span)))
+        }
+    }
+
+    /// Parses a choke field in the range of 0 - 127, the default is used
+    /// when the field is empty.
+    fn data_byte(&self, field: &ConstStr<4>, name: &str, default: Option<u8>) -> Result<u8> {
+        let text = field.as_str().trim();
+
+        match (text.parse::<u8>(), default) {
+            (Ok(value), _) if value < 128 => Ok(value),
+            (_, Some(default)) if text.is_empty() => Ok(default),
+            _ => Err(Error::Mapping(format!(
+                "Enter a choke {name} in the range of 0 - 127 for {}.",
+                self.note
+            )))
+        }
+    }
+
+    #[inline]
+    fn output(&self) -> Output {
+        Output {
+            note: self.note,
+            channel: self.channel
         }
     }
 }